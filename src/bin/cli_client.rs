@@ -7,19 +7,20 @@ use std::ops::Deref;
 use std::{thread, time};
 
 use rpsrtsrs::common::Vec2;
-use rpsrtsrs::network::{Command, Message};
-use rpsrtsrs::state::GameState;
+use rpsrtsrs::formation::Formation;
+use rpsrtsrs::network::{Command, Message, MAX_MESSAGE_SIZE};
 
 use bincode::Options;
 use docopt::Docopt;
 
 static USAGE: &str = "
-Usage: cli_client [-p PORT] [-i IP] [-r ID] (read|move <id> <x> <y>)
+Usage: cli_client [-p PORT] [-i IP] [-r ID | -s] (read|ready|move <id> <x> <y>)
 
 Options:
     -p PORT  The port to connect to [default: 8080].
     -i IP    The ipv4 address to connect to [default: 127.0.0.1].
     -r ID    Reconnect with the given ID
+    -s       Connect as a spectator
 ";
 
 #[derive(Deserialize, Debug)]
@@ -27,8 +28,10 @@ struct Args {
     flag_p: u16,
     flag_i: String,
     flag_r: Option<u32>,
+    flag_s: bool,
 
     cmd_read: bool,
+    cmd_ready: bool,
     arg_id: Option<u32>,
     arg_x: Option<f64>,
     arg_y: Option<f64>,
@@ -51,7 +54,7 @@ fn main() {
     );
 
     let mut stream = TcpStream::connect((host.deref(), port)).unwrap();
-    let bincode = bincode::DefaultOptions::new().with_limit(1024);
+    let bincode = bincode::DefaultOptions::new().with_limit(MAX_MESSAGE_SIZE);
 
     match reconnect {
         Some(id) => {
@@ -59,6 +62,11 @@ fn main() {
                 .serialize_into(&mut stream, &Message::ClientReconnect(id.into()))
                 .unwrap();
         }
+        None if args.flag_s => {
+            bincode
+                .serialize_into(&mut stream, &Message::SpectatorHello)
+                .unwrap();
+        }
         None => {
             bincode
                 .serialize_into(&mut stream, &Message::ClientHello)
@@ -68,11 +76,17 @@ fn main() {
     let server_hello: Result<Message, _> = bincode.deserialize_from(&mut stream);
     println!("{:?}", server_hello);
 
-    if cmd_read {
+    if args.cmd_ready {
+        bincode
+            .serialize_into(&mut stream, &Message::Ready(true))
+            .unwrap();
+        stream.flush().unwrap();
+    } else if cmd_read {
         loop {
-            let game_state: Result<GameState, _> = bincode.deserialize_from(&mut stream);
-            match game_state {
-                Ok(game) => println!("{:?}", game),
+            let message: Result<Message, _> = bincode.deserialize_from(&mut stream);
+            match message {
+                Ok(Message::UpdateGamestate(game)) => println!("{:?}", game),
+                Ok(message) => println!("{:?}", message),
                 Err(e) => {
                     println!("{:?}", e);
                     return;
@@ -86,7 +100,11 @@ fn main() {
         bincode
             .serialize_into(
                 &mut stream,
-                &Message::Command(Command::Move(id.into(), Vec2::new(x, y))),
+                &Message::Command(Command::Move(
+                    vec![id.into()],
+                    Vec2::new(x, y),
+                    Formation::default(),
+                )),
             )
             .unwrap();
         stream.flush().unwrap();
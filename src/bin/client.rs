@@ -7,7 +7,7 @@ extern crate glutin_window;
 #[cfg(feature = "include_sdl2")]
 extern crate sdl2_window;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 #[cfg(feature = "include_glfw")]
 use glfw_window::GlfwWindow as Window;
@@ -25,18 +25,23 @@ use texture::TextureSettings;
 use rpsrtsrs::client::*;
 
 static USAGE: &str = "
-Usage: client [-p PORT] [-i IP]
+Usage: client [-p PORT] [-i IP] [-r ID] [-c FILE] [--replay FILE]
 
 Options:
-    -p PORT  The port to listen on [default: 8080].
-    -i IP    The ipv4 address to listen on [default: 127.0.0.1].
+    -p PORT  The port of the server, instead of the last used one.
+    -i IP    The address of the server, instead of the last used one.
     -r ID    Reconnect with the given ID
+    -c FILE  The configuration file, ~/.config/rpsrtsrs/client.json if not given.
+    --replay FILE  Play a replay recorded by the server instead of showing the menu.
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
-    flag_p: u16,
-    flag_i: String,
+    flag_p: Option<u16>,
+    flag_i: Option<String>,
+    flag_r: Option<u32>,
+    flag_c: Option<String>,
+    flag_replay: Option<String>,
 }
 
 fn main() {
@@ -58,8 +63,30 @@ fn main() {
     let texture_settings = TextureSettings::new();
     let cache = &mut GlyphCache::new(font_path, (), texture_settings).unwrap();
 
+    let config_path = match args.flag_c {
+        Some(path) => Some(PathBuf::from(path)),
+        None => config::Config::default_path(),
+    };
+    let mut config = match config_path {
+        Some(path) => config::Config::load(path),
+        None => config::Config::default(),
+    };
+    if let Some(ip) = args.flag_i {
+        config.server.host = ip;
+    }
+    if let Some(port) = args.flag_p {
+        config.server.port = port;
+    }
+    if let Some(id) = args.flag_r {
+        let server = config.server.clone();
+        config.set_client_id(&server, id.into());
+    }
+
     // Create a new game and run it.
-    let mut app = App::new(GlGraphics::new(opengl), args.flag_i, args.flag_p);
+    let mut app = App::new(GlGraphics::new(opengl), config);
+    if let Some(path) = args.flag_replay {
+        app.play_replay(path);
+    }
 
     let mut events = Events::new(EventSettings::new());
     while let Some(e) = events.next(&mut window) {
@@ -73,6 +100,14 @@ fn main() {
             }
         }
 
+        if let Some(button) = e.release_args() {
+            app.on_button_release(&button);
+        }
+
+        if let Some(text) = e.text_args() {
+            app.on_text(&text);
+        }
+
         if let Some(args) = e.mouse_cursor_args() {
             app.on_mouse_move(args.into());
         }
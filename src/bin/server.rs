@@ -2,24 +2,34 @@
 extern crate serde_derive;
 
 use std::ops::Deref;
+use std::thread;
 
 use docopt::Docopt;
 
-use rpsrtsrs::server::Server;
+use rpsrtsrs::server::{console, Server};
 
 static USAGE: &str = "
-Usage: server [-p PORT] [-i IP]
+Usage: server [-p PORT] [-i IP] [-n NAME] [-d PORT | --no-discovery] [--record FILE] [--load FILE]
 
 Options:
-    -p PORT  The port to listen on [default: 8080].
-    -i IP    The ipv4 address to listen on [default: 127.0.0.1].
-    -r ID    Reconnect with the given ID
+    -p PORT         The port to listen on [default: 8080].
+    -i IP           The ipv4 address to listen on [default: 127.0.0.1].
+    -n NAME         The name announced to clients in the local network [default: rpsrtsrs].
+    -d PORT         The UDP port to answer discovery probes on [default: 8079].
+    --no-discovery  Don't answer discovery probes.
+    --record FILE   Record the match into a replay file.
+    --load FILE     Continue a match saved with the console command 'save FILE'.
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_p: u16,
     flag_i: String,
+    flag_n: String,
+    flag_d: u16,
+    flag_no_discovery: bool,
+    flag_record: Option<String>,
+    flag_load: Option<String>,
 }
 
 fn main() {
@@ -30,7 +40,26 @@ fn main() {
     let host = args.flag_i;
     let port = args.flag_p;
 
-    let server =
+    let mut server =
         Server::new((host.deref(), port), (800.0, 600.0)).expect("Could not initialize server");
+    server.set_name(args.flag_n);
+    if args.flag_no_discovery {
+        server.set_discovery_port(None);
+    } else {
+        server.set_discovery_port(Some(args.flag_d));
+    }
+    if let Some(path) = args.flag_load {
+        server
+            .load_game(&path)
+            .unwrap_or_else(|e| panic!("Could not load {}: {}", path, e));
+    }
+    if let Some(path) = args.flag_record {
+        server
+            .set_replay_file(&path)
+            .unwrap_or_else(|e| panic!("Could not create replay file {}: {}", path, e));
+    }
+
+    let console_server = server.clone();
+    thread::spawn(move || console::run(console_server));
     server.serve();
 }
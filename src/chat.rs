@@ -0,0 +1,161 @@
+//! Chat messages between players, in the lobby and during the match.
+
+use crate::state::ClientId;
+
+/// Maximum length of a chat message in characters
+pub const MAX_CHAT_LENGTH: usize = 200;
+
+/// Number of messages the server keeps for delivery
+const MAX_LOG_LENGTH: usize = 100;
+
+/// Who receives a chat message.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+pub enum ChatChannel {
+    All,
+    /// Players in the same team as the sender
+    Team,
+    /// A single player
+    Private(ClientId),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct ChatMessage {
+    pub from: ClientId,
+    pub channel: ChatChannel,
+    pub text: String,
+}
+
+/// Messages posted on the server, each client fetches the ones addressed to it.
+#[derive(Debug, Default)]
+pub struct ChatLog {
+    /// Messages with their sequence number and the clients that may read them
+    messages: Vec<(u64, ChatMessage, Vec<ClientId>)>,
+    next_sequence: u64,
+}
+
+impl ChatLog {
+    pub fn new() -> ChatLog {
+        ChatLog::default()
+    }
+
+    /// Add a message. `team` returns the team of a player, `None` if the player doesn't exist.
+    pub fn post<F: Fn(ClientId) -> Option<usize>>(
+        &mut self,
+        from: ClientId,
+        channel: ChatChannel,
+        text: &str,
+        players: &[ClientId],
+        team: F,
+    ) {
+        let text: String = text.trim().chars().take(MAX_CHAT_LENGTH).collect();
+        let sender_team = team(from);
+        if text.is_empty() || sender_team.is_none() {
+            return;
+        }
+        let recipients = players
+            .iter()
+            .cloned()
+            .filter(|id| match channel {
+                ChatChannel::All => true,
+                ChatChannel::Team => team(*id) == sender_team,
+                ChatChannel::Private(to) => *id == to || *id == from,
+            })
+            .collect();
+        let message = ChatMessage {
+            from,
+            channel,
+            text,
+        };
+        self.messages
+            .push((self.next_sequence, message, recipients));
+        self.next_sequence += 1;
+        if self.messages.len() > MAX_LOG_LENGTH {
+            self.messages.remove(0);
+        }
+    }
+
+    /// Return the sequence number the next message will get.
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    /// Return the messages for the client with a sequence number of at least `since`.
+    pub fn messages_for(&self, client: ClientId, since: u64) -> Vec<ChatMessage> {
+        self.messages
+            .iter()
+            .filter(|(sequence, _, recipients)| *sequence >= since && recipients.contains(&client))
+            .map(|(_, message, _)| message.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_with(channel: ChatChannel) -> ChatLog {
+        let mut log = ChatLog::new();
+        let players = [ClientId(1), ClientId(2), ClientId(3)];
+        // Players 1 and 2 are in the same team
+        let team = |id: ClientId| match id.0 {
+            1 | 2 => Some(0),
+            3 => Some(1),
+            _ => None,
+        };
+        log.post(ClientId(1), channel, " hello ", &players, team);
+        log
+    }
+
+    fn receives(log: &ChatLog, id: u32) -> bool {
+        !log.messages_for(ClientId(id), 0).is_empty()
+    }
+
+    #[test]
+    fn test_channels() {
+        let log = log_with(ChatChannel::All);
+        assert!(receives(&log, 1) && receives(&log, 2) && receives(&log, 3));
+        assert_eq!(log.messages_for(ClientId(3), 0)[0].text, "hello");
+
+        let log = log_with(ChatChannel::Team);
+        assert!(receives(&log, 1) && receives(&log, 2) && !receives(&log, 3));
+
+        let log = log_with(ChatChannel::Private(ClientId(3)));
+        assert!(receives(&log, 1) && !receives(&log, 2) && receives(&log, 3));
+    }
+
+    #[test]
+    fn test_sequence() {
+        //! Clients only fetch the messages they haven't seen yet.
+        let mut log = log_with(ChatChannel::All);
+        let since = log.next_sequence();
+        assert!(log.messages_for(ClientId(1), since).is_empty());
+        log.post(ClientId(2), ChatChannel::All, "bye", &[ClientId(1)], |_| {
+            Some(0)
+        });
+        let messages = log.messages_for(ClientId(1), since);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].text, "bye");
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        //! Empty messages and messages of unknown players are dropped, long ones are cut.
+        let mut log = ChatLog::new();
+        let players = [ClientId(1)];
+        log.post(ClientId(1), ChatChannel::All, "   ", &players, |_| Some(0));
+        log.post(ClientId(1), ChatChannel::All, "hi", &players, |_| None);
+        assert_eq!(log.next_sequence(), 0);
+
+        log.post(
+            ClientId(1),
+            ChatChannel::All,
+            &"a".repeat(1000),
+            &players,
+            |_| Some(0),
+        );
+        assert_eq!(
+            log.messages_for(ClientId(1), 0)[0].text.len(),
+            MAX_CHAT_LENGTH
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Mapping between screen and world coordinates.
+
+use graphics::math::Matrix2d;
+use graphics::Transformed;
+
+use crate::common::Vec2;
+use crate::state::WorldState;
+
+/// Zoom factor applied per step of the mouse wheel
+const ZOOM_STEP: f64 = 1.2;
+
+const MIN_ZOOM: f64 = 0.25;
+const MAX_ZOOM: f64 = 4.0;
+
+/// Distance in pixels from the window border in which the camera starts to pan
+const EDGE_PAN_MARGIN: f64 = 20.0;
+
+/// Panning speed at the window border in pixels per second
+const EDGE_PAN_SPEED: f64 = 600.0;
+
+/// Visible part of the world.
+#[derive(Debug, Clone)]
+pub struct Camera {
+    /// World position shown at the top left corner of the window
+    position: Vec2,
+    /// Pixels per world unit
+    zoom: f64,
+    /// Size of the window in pixels
+    window_size: Vec2,
+    /// Bounds the center of the view is kept in
+    world: Option<WorldState>,
+}
+
+impl Camera {
+    pub fn new() -> Camera {
+        Camera {
+            position: Vec2::new(0.0, 0.0),
+            zoom: 1.0,
+            window_size: Vec2::new(0.0, 0.0),
+            world: None,
+        }
+    }
+
+    pub fn set_world(&mut self, world: &WorldState) {
+        self.world = Some(world.clone());
+        self.clamp();
+    }
+
+    pub fn zoom(&self) -> f64 {
+        self.zoom
+    }
+
+    pub fn window_size(&self) -> Vec2 {
+        self.window_size
+    }
+
+    /// Resize the window, keeping the world position in its center in place.
+    pub fn set_window_size(&mut self, window_size: Vec2) {
+        if window_size != self.window_size {
+            let center = self.center();
+            self.window_size = window_size;
+            self.center_on(center);
+        }
+    }
+
+    /// Convert a position on the screen to world coordinates.
+    pub fn to_world(&self, position: Vec2) -> Vec2 {
+        position / self.zoom + self.position
+    }
+
+    /// Convert a world position to a position on the screen.
+    pub fn to_screen(&self, position: Vec2) -> Vec2 {
+        (position - self.position) * self.zoom
+    }
+
+    /// Return a transformation from world to screen coordinates, based on the window transform.
+    pub fn transform(&self, transform: Matrix2d) -> Matrix2d {
+        transform
+            .scale(self.zoom, self.zoom)
+            .trans(-self.position.x, -self.position.y)
+    }
+
+    /// Return the top left and bottom right world positions of the visible area.
+    pub fn visible_area(&self) -> (Vec2, Vec2) {
+        (
+            self.to_world(Vec2::new(0.0, 0.0)),
+            self.to_world(self.window_size),
+        )
+    }
+
+    /// Return the world position in the middle of the screen.
+    pub fn center(&self) -> Vec2 {
+        self.to_world(self.window_size / 2.0)
+    }
+
+    /// Move the camera so that the given world position is in the middle of the screen.
+    pub fn center_on(&mut self, position: Vec2) {
+        self.position = position - self.window_size / (2.0 * self.zoom);
+        self.clamp();
+    }
+
+    /// Move the camera by the given distance in pixels.
+    pub fn pan(&mut self, delta: Vec2) {
+        self.position += delta / self.zoom;
+        self.clamp();
+    }
+
+    /// Zoom in (positive `steps`) or out (negative `steps`), keeping the world position under
+    /// the given screen position in place.
+    pub fn zoom_at(&mut self, screen: Vec2, steps: f64) {
+        let anchor = self.to_world(screen);
+        self.zoom = num::clamp(self.zoom * ZOOM_STEP.powf(steps), MIN_ZOOM, MAX_ZOOM);
+        self.position = anchor - screen / self.zoom;
+        self.clamp();
+    }
+
+    /// Pan the camera when the cursor is close to the window border.
+    ///
+    /// `dt` is the time since the last update in seconds.
+    pub fn edge_pan(&mut self, cursor: Vec2, dt: f64) {
+        let size = self.window_size;
+        // Don't pan when the cursor left the window
+        if cursor.x < 0.0 || cursor.y < 0.0 || cursor.x > size.x || cursor.y > size.y {
+            return;
+        }
+        let direction = |position: f64, size: f64| {
+            if position < EDGE_PAN_MARGIN {
+                -1.0
+            } else if position > size - EDGE_PAN_MARGIN {
+                1.0
+            } else {
+                0.0
+            }
+        };
+        let direction = Vec2::new(direction(cursor.x, size.x), direction(cursor.y, size.y));
+        if direction != Vec2::new(0.0, 0.0) {
+            self.pan(direction * EDGE_PAN_SPEED * dt);
+        }
+    }
+
+    /// Keep the center of the view inside the world.
+    fn clamp(&mut self) {
+        if let Some(ref world) = self.world {
+            let half = self.window_size / (2.0 * self.zoom);
+            self.position = world.clamp(self.position + half) - half;
+        }
+    }
+}
+
+impl Default for Camera {
+    fn default() -> Camera {
+        Camera::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera() -> Camera {
+        let mut camera = Camera::new();
+        camera.set_window_size(Vec2::new(400.0, 300.0));
+        camera.set_world(&WorldState::new(800.0, 600.0));
+        camera.center_on(Vec2::new(400.0, 300.0));
+        camera
+    }
+
+    #[test]
+    fn test_transform() {
+        //! Screen and world coordinates convert into each other.
+        let mut camera = camera();
+        camera.zoom_at(Vec2::new(0.0, 0.0), 2.0);
+        let position = Vec2::new(123.0, 456.0);
+        let screen = camera.to_screen(position);
+        assert!((camera.to_world(screen) - position).x.abs() < 1e-9);
+        assert!((camera.to_world(screen) - position).y.abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_zoom_to_cursor() {
+        //! The world position under the cursor stays in place while zooming.
+        let mut camera = camera();
+        let cursor = Vec2::new(100.0, 50.0);
+        let before = camera.to_world(cursor);
+        camera.zoom_at(cursor, 1.0);
+        let after = camera.to_world(cursor);
+        assert!((after.x - before.x).abs() < 1e-9);
+        assert!((after.y - before.y).abs() < 1e-9);
+        assert!(camera.zoom > 1.0);
+    }
+
+    #[test]
+    fn test_fractional_zoom() {
+        //! Small scroll steps, e.g. from a touchpad, zoom a little in the right direction.
+        let mut camera = camera();
+        camera.zoom_at(Vec2::new(0.0, 0.0), 0.1);
+        assert!(camera.zoom > 1.0 && camera.zoom < ZOOM_STEP);
+        camera.zoom_at(Vec2::new(0.0, 0.0), -0.2);
+        assert!(camera.zoom < 1.0);
+    }
+
+    #[test]
+    fn test_zoom_limits() {
+        let mut camera = camera();
+        camera.zoom_at(Vec2::new(0.0, 0.0), 100.0);
+        assert_eq!(camera.zoom, MAX_ZOOM);
+        camera.zoom_at(Vec2::new(0.0, 0.0), -100.0);
+        assert_eq!(camera.zoom, MIN_ZOOM);
+    }
+
+    #[test]
+    fn test_clamp() {
+        //! The center of the view can't leave the world.
+        let mut camera = camera();
+        camera.pan(Vec2::new(-10000.0, 10000.0));
+        assert_eq!(camera.center(), Vec2::new(0.0, 600.0));
+        camera.center_on(Vec2::new(1000.0, 300.0));
+        assert_eq!(camera.center(), Vec2::new(800.0, 300.0));
+    }
+
+    #[test]
+    fn test_edge_pan() {
+        //! The camera pans towards the border the cursor is close to.
+        let mut camera = camera();
+        let center = camera.center();
+        camera.edge_pan(Vec2::new(399.0, 150.0), 0.1);
+        assert!(camera.center().x > center.x);
+        assert_eq!(camera.center().y, center.y);
+
+        // Nothing happens in the middle of the window or outside of it
+        let center = camera.center();
+        camera.edge_pan(Vec2::new(200.0, 150.0), 0.1);
+        camera.edge_pan(Vec2::new(-5.0, 150.0), 0.1);
+        assert_eq!(camera.center(), center);
+    }
+}
@@ -0,0 +1,220 @@
+//! Chat log shown during the match and the input line for new messages.
+
+use std::time::{Duration, Instant};
+
+use graphics::{Context, Text, Transformed};
+use opengl_graphics::{GlGraphics, GlyphCache};
+use piston::input::Key;
+
+use crate::chat::{ChatChannel, ChatMessage, MAX_CHAT_LENGTH};
+use crate::colors::{CHAT, CHAT_INPUT};
+use crate::common::Vec2;
+use crate::network::Message;
+use crate::state::ClientId;
+
+const FONT_SIZE: u32 = 14;
+const LINE_HEIGHT: f64 = 18.0;
+
+/// Number of received messages kept
+const MAX_MESSAGES: usize = 100;
+
+/// Number of messages shown at once
+const VISIBLE_MESSAGES: usize = 8;
+
+/// Time a message stays visible after it was received, including the time it fades out
+const VISIBLE_TIME: Duration = Duration::from_secs(10);
+const FADE_TIME: Duration = Duration::from_secs(2);
+
+/// Message being typed
+#[derive(Debug, Clone, PartialEq)]
+struct Input {
+    channel: ChatChannel,
+    text: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ChatBox {
+    /// Received messages with the time they arrived
+    log: Vec<(Instant, ChatMessage)>,
+    input: Option<Input>,
+}
+
+/// Return the message as shown in the log.
+pub fn label(message: &ChatMessage, own_id: Option<ClientId>) -> String {
+    let channel = match message.channel {
+        ChatChannel::All => "All".to_string(),
+        ChatChannel::Team => "Team".to_string(),
+        ChatChannel::Private(to) if Some(message.from) == own_id => format!("To {}", to),
+        ChatChannel::Private(_) => "Private".to_string(),
+    };
+    format!("[{}] Player {}: {}", channel, message.from, message.text)
+}
+
+fn channel_label(channel: ChatChannel) -> String {
+    match channel {
+        ChatChannel::All => "All".to_string(),
+        ChatChannel::Team => "Team".to_string(),
+        ChatChannel::Private(to) => format!("To {}", to),
+    }
+}
+
+impl ChatBox {
+    pub fn new() -> ChatBox {
+        ChatBox::default()
+    }
+
+    pub fn receive(&mut self, message: ChatMessage, now: Instant) {
+        self.log.push((now, message));
+        if self.log.len() > MAX_MESSAGES {
+            self.log.remove(0);
+        }
+    }
+
+    pub fn messages(&self) -> impl Iterator<Item = &ChatMessage> {
+        self.log.iter().map(|(_, message)| message)
+    }
+
+    /// Return whether the user is typing a message, so keys shouldn't trigger game actions.
+    pub fn is_typing(&self) -> bool {
+        self.input.is_some()
+    }
+
+    pub fn open(&mut self) {
+        self.input = Some(Input {
+            channel: ChatChannel::All,
+            text: String::new(),
+        });
+    }
+
+    /// Handle a key press while typing and return the message to send, if any.
+    ///
+    /// Tab cycles the channel through all players, the team and every other player in
+    /// `players`, Return sends the message or closes the input if it's empty.
+    pub fn on_key(&mut self, key: Key, own_id: ClientId, players: &[ClientId]) -> Option<Message> {
+        let input = self.input.as_mut()?;
+        match key {
+            Key::Return => {
+                let input = self.input.take()?;
+                if input.text.trim().is_empty() {
+                    None
+                } else {
+                    Some(Message::Chat(input.channel, input.text))
+                }
+            }
+            Key::Backspace => {
+                input.text.pop();
+                None
+            }
+            Key::Tab => {
+                let mut channels = vec![ChatChannel::All, ChatChannel::Team];
+                channels.extend(
+                    players
+                        .iter()
+                        .filter(|id| **id != own_id)
+                        .map(|id| ChatChannel::Private(*id)),
+                );
+                let index = channels
+                    .iter()
+                    .position(|channel| *channel == input.channel)
+                    .unwrap_or(0);
+                input.channel = channels[(index + 1) % channels.len()];
+                None
+            }
+            _ => None,
+        }
+    }
+
+    pub fn on_text(&mut self, text: &str) {
+        if let Some(ref mut input) = self.input {
+            let room = MAX_CHAT_LENGTH.saturating_sub(input.text.chars().count());
+            input
+                .text
+                .extend(text.chars().filter(|c| !c.is_control()).take(room));
+        }
+    }
+
+    /// Draw the recent messages, fading out, above `bottom_left` and the input line below them.
+    pub fn render(
+        &self,
+        now: Instant,
+        own_id: Option<ClientId>,
+        bottom_left: Vec2,
+        c: &Context,
+        cache: &mut GlyphCache<'_>,
+        gl: &mut GlGraphics,
+    ) {
+        let mut y = bottom_left.y;
+        if let Some(ref input) = self.input {
+            let line = format!("({}) {}_", channel_label(input.channel), input.text);
+            let transform = c.transform.trans(bottom_left.x, y);
+            Text::new_color(CHAT_INPUT, FONT_SIZE)
+                .draw(&line, cache, &c.draw_state, transform, gl)
+                .unwrap();
+            y -= LINE_HEIGHT;
+        }
+
+        let skip = self.log.len().saturating_sub(VISIBLE_MESSAGES);
+        for (received, message) in self.log.iter().skip(skip).rev() {
+            let age = now.duration_since(*received);
+            // All recent messages stay readable while typing
+            let alpha = if self.is_typing() {
+                1.0
+            } else if age >= VISIBLE_TIME {
+                continue;
+            } else {
+                ((VISIBLE_TIME - age).as_secs_f64() / FADE_TIME.as_secs_f64()).min(1.0)
+            };
+            let mut color = CHAT;
+            color[3] *= alpha as f32;
+            let transform = c.transform.trans(bottom_left.x, y);
+            Text::new_color(color, FONT_SIZE)
+                .draw(&label(message, own_id), cache, &c.draw_state, transform, gl)
+                .unwrap();
+            y -= LINE_HEIGHT;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input() {
+        //! Typed text is sent to the chosen channel, keys do nothing while not typing.
+        let players = [ClientId(1), ClientId(2)];
+        let mut chat = ChatBox::new();
+        assert_eq!(chat.on_key(Key::Return, ClientId(1), &players), None);
+        chat.on_text("ignored");
+
+        chat.open();
+        assert!(chat.is_typing());
+        chat.on_text("hello");
+        chat.on_key(Key::Tab, ClientId(1), &players);
+        chat.on_key(Key::Tab, ClientId(1), &players);
+        assert_eq!(
+            chat.on_key(Key::Return, ClientId(1), &players),
+            Some(Message::Chat(
+                ChatChannel::Private(ClientId(2)),
+                "hello".to_string()
+            ))
+        );
+        assert!(!chat.is_typing());
+
+        // Sending an empty message just closes the input
+        chat.open();
+        assert_eq!(chat.on_key(Key::Return, ClientId(1), &players), None);
+        assert!(!chat.is_typing());
+    }
+
+    #[test]
+    fn test_label() {
+        let message = ChatMessage {
+            from: ClientId(1),
+            channel: ChatChannel::Private(ClientId(2)),
+            text: "hi".to_string(),
+        };
+        assert_eq!(label(&message, Some(ClientId(1))), "[To 2] Player 1: hi");
+        assert_eq!(label(&message, Some(ClientId(2))), "[Private] Player 1: hi");
+    }
+}
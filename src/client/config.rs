@@ -0,0 +1,187 @@
+//! Client settings and connection history, persisted as JSON.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::state::ClientId;
+
+/// Maximum number of servers kept in the history
+const MAX_RECENT_SERVERS: usize = 5;
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct ServerAddress {
+    pub host: String,
+    pub port: u16,
+}
+
+impl ServerAddress {
+    pub fn new<T: Into<String>>(host: T, port: u16) -> ServerAddress {
+        ServerAddress {
+            host: host.into(),
+            port,
+        }
+    }
+}
+
+impl fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// Options changed in the settings screen.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[serde(default)]
+pub struct Settings {
+    /// Pan the camera when the cursor touches the window border
+    pub edge_pan: bool,
+    /// Draw health bars above units
+    pub health_bars: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            edge_pan: true,
+            health_bars: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// Server shown in the main menu
+    pub server: ServerAddress,
+    /// ID of the player of the last game on each server by its `host:port`, to reconnect to it
+    pub client_ids: BTreeMap<String, ClientId>,
+    /// Servers connected to, the most recent first
+    pub recent_servers: Vec<ServerAddress>,
+    pub settings: Settings,
+    /// File the configuration is saved to
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            server: ServerAddress::new("127.0.0.1", 8080),
+            client_ids: BTreeMap::new(),
+            recent_servers: vec![],
+            settings: Settings::default(),
+            path: None,
+        }
+    }
+}
+
+impl Config {
+    /// Return the default location of the configuration file.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(config_dir.join("rpsrtsrs").join("client.json"))
+    }
+
+    /// Load the configuration from `path`, falling back to the defaults if it can't be read.
+    pub fn load<P: AsRef<Path>>(path: P) -> Config {
+        let path = path.as_ref();
+        let mut config = match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Invalid configuration file {:?}: {}", path, e);
+                Config::default()
+            }),
+            Err(_) => Config::default(),
+        };
+        config.path = Some(path.to_path_buf());
+        config
+    }
+
+    /// Write the configuration back to the file it was loaded from.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(ref path) = self.path {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(path, serde_json::to_string_pretty(self)?)?;
+        }
+        Ok(())
+    }
+
+    /// Return the ID to reconnect to the server with, if any.
+    pub fn client_id(&self, server: &ServerAddress) -> Option<ClientId> {
+        self.client_ids.get(&server.to_string()).copied()
+    }
+
+    pub fn set_client_id(&mut self, server: &ServerAddress, id: ClientId) {
+        self.client_ids.insert(server.to_string(), id);
+    }
+
+    /// Move the server to the front of the history.
+    ///
+    /// The IDs of servers that drop out of the history are forgotten.
+    pub fn add_recent_server(&mut self, server: ServerAddress) {
+        self.recent_servers.retain(|recent| *recent != server);
+        self.recent_servers.insert(0, server);
+        self.recent_servers.truncate(MAX_RECENT_SERVERS);
+        let recent: Vec<String> = self.recent_servers.iter().map(|s| s.to_string()).collect();
+        self.client_ids.retain(|server, _| recent.contains(server));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::TempPath;
+
+    #[test]
+    fn test_recent_servers() {
+        //! The history has no duplicates and is limited in length.
+        let mut config = Config::default();
+        for port in 0..10 {
+            config.add_recent_server(ServerAddress::new("localhost", port));
+        }
+        config.add_recent_server(ServerAddress::new("localhost", 7));
+        let ports: Vec<u16> = config.recent_servers.iter().map(|s| s.port).collect();
+        assert_eq!(ports, vec![7, 9, 8, 6, 5]);
+    }
+
+    #[test]
+    fn test_client_ids() {
+        //! IDs belong to their server and are forgotten with it.
+        let mut config = Config::default();
+        let first = ServerAddress::new("localhost", 1);
+        let second = ServerAddress::new("localhost", 2);
+        config.set_client_id(&first, ClientId(3));
+        config.add_recent_server(first.clone());
+        assert_eq!(config.client_id(&first), Some(ClientId(3)));
+        assert_eq!(config.client_id(&second), None);
+
+        for port in 2..2 + MAX_RECENT_SERVERS as u16 {
+            config.add_recent_server(ServerAddress::new("localhost", port));
+        }
+        assert_eq!(config.client_id(&first), None);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = TempPath::new("config");
+        let path = dir.join("client.json");
+        let mut config = Config::load(&path);
+        assert_eq!(config, Config::load(&path));
+
+        let server = ServerAddress::new("example.com", 1234);
+        config.set_client_id(&server, ClientId(3));
+        config.settings.edge_pan = false;
+        config.add_recent_server(server);
+        config.save().unwrap();
+
+        assert_eq!(Config::load(&path), config);
+    }
+}
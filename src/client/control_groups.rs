@@ -0,0 +1,128 @@
+//! Selections stored under the number keys.
+
+use std::time::Instant;
+
+use piston::input::Key;
+
+use super::selection::DOUBLE_CLICK_TIME;
+use crate::common::Vec2;
+use crate::state::{GameState, UnitId};
+
+/// Number of control groups, recalled with the keys 1 to 9
+pub const CONTROL_GROUPS: usize = 9;
+
+#[derive(Debug, Clone, Default)]
+pub struct ControlGroups {
+    groups: [Vec<UnitId>; CONTROL_GROUPS],
+    /// Time and index of the last recalled group, to detect double taps
+    last_recall: Option<(Instant, usize)>,
+}
+
+impl ControlGroups {
+    pub fn new() -> ControlGroups {
+        ControlGroups::default()
+    }
+
+    /// Return the index of the group bound to the key, if any.
+    pub fn index(key: Key) -> Option<usize> {
+        match key {
+            Key::D1 => Some(0),
+            Key::D2 => Some(1),
+            Key::D3 => Some(2),
+            Key::D4 => Some(3),
+            Key::D5 => Some(4),
+            Key::D6 => Some(5),
+            Key::D7 => Some(6),
+            Key::D8 => Some(7),
+            Key::D9 => Some(8),
+            _ => None,
+        }
+    }
+
+    pub fn store(&mut self, index: usize, units: &[UnitId]) {
+        info!("Store control group {}", index + 1);
+        self.groups[index] = units.to_vec();
+    }
+
+    /// Return the units of the group at time `now` and whether it was recalled twice in a row,
+    /// which centers the camera on it.
+    pub fn recall(&mut self, index: usize, now: Instant) -> (Vec<UnitId>, bool) {
+        let double_tap = match self.last_recall {
+            Some((time, last)) => last == index && now.duration_since(time) < DOUBLE_CLICK_TIME,
+            None => false,
+        };
+        self.last_recall = Some((now, index));
+        (self.groups[index].clone(), double_tap)
+    }
+
+    /// Remove units that don't exist anymore from all groups.
+    pub fn prune(&mut self, game: &GameState) {
+        for group in self.groups.iter_mut() {
+            group.retain(|id| game.find_unit(*id).is_some());
+        }
+    }
+}
+
+/// Return the center of the given units that still exist, `None` if there are none.
+pub fn center(game: &GameState, units: &[UnitId]) -> Option<Vec2> {
+    let positions: Vec<Vec2> = units
+        .iter()
+        .filter_map(|id| game.find_unit(*id))
+        .map(|(_, unit)| unit.position)
+        .collect();
+    if positions.is_empty() {
+        return None;
+    }
+    Some(positions.iter().fold(Vec2::new(0.0, 0.0), |sum, p| sum + p) / positions.len() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::state::{Player, Unit};
+
+    fn ids(ids: &[u32]) -> Vec<UnitId> {
+        ids.iter().map(|id| UnitId(*id)).collect()
+    }
+
+    #[test]
+    fn test_store_and_recall() {
+        //! Recalling a group twice in quick succession is a double tap.
+        let mut groups = ControlGroups::new();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        groups.store(0, &ids(&[1, 2]));
+        groups.store(8, &ids(&[3]));
+        assert_eq!(ControlGroups::index(Key::D9), Some(8));
+        assert_eq!(ControlGroups::index(Key::D0), None);
+
+        assert_eq!(groups.recall(0, at(0)), (ids(&[1, 2]), false));
+        assert_eq!(groups.recall(0, at(100)), (ids(&[1, 2]), true));
+        assert_eq!(groups.recall(8, at(200)), (ids(&[3]), false));
+        assert_eq!(groups.recall(0, at(300)), (ids(&[1, 2]), false));
+        assert_eq!(groups.recall(0, at(1000)), (ids(&[1, 2]), false));
+        assert_eq!(groups.recall(1, at(1100)), (vec![], false));
+    }
+
+    #[test]
+    fn test_prune_and_center() {
+        //! Destroyed units leave the groups and don't count for the center.
+        let mut game = GameState::new();
+        let mut player = Player::new(1);
+        player.units.push(Unit::new(1, Vec2::new(100.0, 100.0)));
+        player.units.push(Unit::new(2, Vec2::new(300.0, 200.0)));
+        game.players.push(player);
+
+        let mut groups = ControlGroups::new();
+        groups.store(0, &ids(&[1, 2, 3]));
+        assert_eq!(
+            center(&game, &ids(&[1, 2, 3])),
+            Some(Vec2::new(200.0, 150.0))
+        );
+        groups.prune(&game);
+        assert_eq!(groups.recall(0, Instant::now()).0, ids(&[1, 2]));
+        assert_eq!(center(&game, &ids(&[3])), None);
+    }
+}
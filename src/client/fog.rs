@@ -0,0 +1,128 @@
+//! Fog of war: the parts of the world the player has never seen or doesn't see right now.
+
+use graphics::math::Matrix2d;
+use graphics::{rectangle, Graphics};
+
+use crate::colors::{FOG_EXPLORED, FOG_UNEXPLORED};
+use crate::common::Vec2;
+use crate::state::{ClientId, GameState, WorldState};
+
+/// Edge length of the square cells the world is divided into, in m
+const CELL_SIZE: f64 = 20.0;
+
+/// Visibility of the world for this client, tracked in cells.
+#[derive(Debug, Clone)]
+pub struct Fog {
+    columns: usize,
+    rows: usize,
+    /// Cells that were seen at any time
+    explored: Vec<bool>,
+    /// Cells currently seen by at least one own unit
+    visible: Vec<bool>,
+}
+
+impl Fog {
+    /// Create a fog covering the whole world, nothing is explored yet.
+    pub fn new(world: &WorldState) -> Fog {
+        let columns = (world.x / CELL_SIZE).ceil().max(1.0) as usize;
+        let rows = (world.y / CELL_SIZE).ceil().max(1.0) as usize;
+        Fog {
+            columns,
+            rows,
+            explored: vec![false; columns * rows],
+            visible: vec![false; columns * rows],
+        }
+    }
+
+    /// Return the index of the cell containing the position, if it is inside the world.
+    fn cell(&self, position: Vec2) -> Option<usize> {
+        if position.x < 0.0 || position.y < 0.0 {
+            return None;
+        }
+        let (column, row) = (
+            (position.x / CELL_SIZE) as usize,
+            (position.y / CELL_SIZE) as usize,
+        );
+        if column < self.columns && row < self.rows {
+            Some(row * self.columns + column)
+        } else {
+            None
+        }
+    }
+
+    fn center(&self, index: usize) -> Vec2 {
+        let (column, row) = (index % self.columns, index / self.columns);
+        Vec2::new(
+            (column as f64 + 0.5) * CELL_SIZE,
+            (row as f64 + 0.5) * CELL_SIZE,
+        )
+    }
+
+    /// Recompute the visible cells from the units of the player and mark them as explored.
+    pub fn update(&mut self, game: &GameState, own_id: ClientId) {
+        for index in 0..self.visible.len() {
+            let visible = game.is_visible_to(own_id, self.center(index));
+            self.visible[index] = visible;
+            self.explored[index] |= visible;
+        }
+    }
+
+    pub fn is_visible(&self, position: Vec2) -> bool {
+        self.cell(position).is_some_and(|index| self.visible[index])
+    }
+
+    pub fn is_explored(&self, position: Vec2) -> bool {
+        self.cell(position)
+            .is_some_and(|index| self.explored[index])
+    }
+
+    /// Cover the cells that aren't visible, `transform` converts world to screen coordinates.
+    pub fn render<G: Graphics>(&self, transform: Matrix2d, gl: &mut G) {
+        for index in 0..self.visible.len() {
+            if self.visible[index] {
+                continue;
+            }
+            let color = if self.explored[index] {
+                FOG_EXPLORED
+            } else {
+                FOG_UNEXPLORED
+            };
+            let corner = self.center(index) - Vec2::new(CELL_SIZE, CELL_SIZE) / 2.0;
+            rectangle(
+                color,
+                [corner.x, corner.y, CELL_SIZE, CELL_SIZE],
+                transform,
+                gl,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Player, Unit};
+
+    #[test]
+    fn test_exploration() {
+        //! Areas stay explored after the units left them, but aren't visible anymore.
+        let world = WorldState::new(2000.0, 500.0);
+        let mut game = GameState::new();
+        let mut player = Player::new(1);
+        player.units.push(Unit::new(1, Vec2::new(100.0, 100.0)));
+        game.players.push(player);
+
+        let mut fog = Fog::new(&world);
+        fog.update(&game, ClientId(1));
+        let start = Vec2::new(110.0, 110.0);
+        let far = Vec2::new(1900.0, 100.0);
+        assert!(fog.is_visible(start) && fog.is_explored(start));
+        assert!(!fog.is_visible(far) && !fog.is_explored(far));
+
+        game.players[0].units[0].position = Vec2::new(1900.0, 100.0);
+        fog.update(&game, ClientId(1));
+        assert!(!fog.is_visible(start) && fog.is_explored(start));
+        assert!(fog.is_visible(far));
+        assert!(!fog.is_visible(Vec2::new(-1.0, 100.0)));
+    }
+}
@@ -0,0 +1,281 @@
+//! Information displayed on top of the game: health bars, the selection panel, the player list,
+//! the pause state and debug statistics.
+
+use graphics::character::CharacterCache;
+use graphics::math::Matrix2d;
+use graphics::{rectangle, Context, Text, Transformed};
+use opengl_graphics::{GlGraphics, GlyphCache};
+
+use crate::colors::{
+    self, HEALTH_BAR, HEALTH_BAR_BACKGROUND, HUD_BACKGROUND, PAUSE_OVERLAY, WHITE, YELLOW,
+};
+use crate::common::Vec2;
+use crate::state::{ClientId, GameState, Order, Player, Unit, MAX_HEALTH, UNIT_SIZE};
+
+const FONT_SIZE: u32 = 14;
+const LINE_HEIGHT: f64 = 18.0;
+
+/// Height of a health bar in m
+const HEALTH_BAR_HEIGHT: f64 = 5.0;
+
+/// Height of the selection panel in pixels
+const PANEL_HEIGHT: f64 = 70.0;
+
+/// Width of a unit's entry in the selection panel in pixels
+const PANEL_ENTRY_WIDTH: f64 = 120.0;
+
+/// Distance of the HUD elements from the window border and each other in pixels
+const MARGIN: f64 = 10.0;
+
+/// Return a short description of what the unit is doing.
+pub fn order_label(unit: &Unit) -> &'static str {
+    match unit.orders.front() {
+        None => "Idle",
+        Some(Order::Move(_)) | Some(Order::FormationMove { .. }) => "Moving",
+        Some(Order::Attack(_)) => "Attacking",
+        Some(Order::AttackMove(_)) => "Attack-moving",
+        Some(Order::HoldPosition) => "Holding",
+        Some(Order::Patrol(..)) => "Patrolling",
+    }
+}
+
+/// Return how many of `count` selected units get an entry in a selection panel of the given
+/// width in pixels. If not all fit, the last entry is left for the number of missing units.
+fn shown_entries(count: usize, width: f64) -> usize {
+    let fitting = ((width / PANEL_ENTRY_WIDTH) as usize).max(1);
+    if count > fitting {
+        fitting - 1
+    } else {
+        count
+    }
+}
+
+/// Draw a bar filled according to the health of the unit, `rect` is `[x, y, width, height]`.
+fn health_bar(unit: &Unit, rect: [f64; 4], transform: Matrix2d, gl: &mut GlGraphics) {
+    let fraction = (unit.health as f64 / MAX_HEALTH as f64).min(1.0);
+    rectangle(HEALTH_BAR_BACKGROUND, rect, transform, gl);
+    rectangle(
+        HEALTH_BAR,
+        [rect[0], rect[1], rect[2] * fraction, rect[3]],
+        transform,
+        gl,
+    );
+}
+
+/// Draw a health bar above the unit, `transform` converts world to screen coordinates.
+pub fn render_health_bar(unit: &Unit, transform: Matrix2d, gl: &mut GlGraphics) {
+    let rect = [
+        unit.position.x - 0.5 * UNIT_SIZE,
+        unit.position.y - 0.75 * UNIT_SIZE - HEALTH_BAR_HEIGHT,
+        UNIT_SIZE,
+        HEALTH_BAR_HEIGHT,
+    ];
+    health_bar(unit, rect, transform, gl);
+}
+
+/// Draw the selected units' weapon, health and current order at the bottom of the window,
+/// starting at `left`.
+pub fn render_selection(
+    units: &[&Unit],
+    left: f64,
+    window_size: Vec2,
+    c: &Context,
+    cache: &mut GlyphCache<'_>,
+    gl: &mut GlGraphics,
+) {
+    if units.is_empty() {
+        return;
+    }
+    let text = Text::new_color(WHITE, FONT_SIZE);
+    let top = window_size.y - MARGIN - PANEL_HEIGHT;
+    let width = window_size.x - MARGIN - left;
+    rectangle(
+        HUD_BACKGROUND,
+        [left, top, width, PANEL_HEIGHT],
+        c.transform,
+        gl,
+    );
+
+    let shown = shown_entries(units.len(), width);
+    for (i, unit) in units.iter().take(shown).enumerate() {
+        let x = left + MARGIN + i as f64 * PANEL_ENTRY_WIDTH;
+        let transform = c.transform.trans(x, top + LINE_HEIGHT);
+        text.draw(
+            &format!("{:?}", unit.weapon),
+            cache,
+            &c.draw_state,
+            transform,
+            gl,
+        )
+        .unwrap();
+        health_bar(
+            unit,
+            [
+                x,
+                top + LINE_HEIGHT + 6.0,
+                PANEL_ENTRY_WIDTH - 2.0 * MARGIN,
+                HEALTH_BAR_HEIGHT,
+            ],
+            c.transform,
+            gl,
+        );
+        let transform = c.transform.trans(x, top + 3.0 * LINE_HEIGHT);
+        text.draw(order_label(unit), cache, &c.draw_state, transform, gl)
+            .unwrap();
+    }
+    if shown < units.len() {
+        let x = left + MARGIN + shown as f64 * PANEL_ENTRY_WIDTH;
+        let transform = c.transform.trans(x, top + LINE_HEIGHT);
+        text.draw(
+            &format!("+{}", units.len() - shown),
+            cache,
+            &c.draw_state,
+            transform,
+            gl,
+        )
+        .unwrap();
+    }
+}
+
+/// Return the entry of the player in the player list.
+///
+/// Clients that take part in the match only receive the enemy units they see, so those counts
+/// are marked as visible units.
+fn player_label(player: &Player, own_id: Option<ClientId>, fog: bool) -> String {
+    if Some(player.id) == own_id {
+        format!("Player {} (you): {}", player.id, player.units.len())
+    } else if fog {
+        format!("Player {}: {} visible", player.id, player.units.len())
+    } else {
+        format!("Player {}: {}", player.id, player.units.len())
+    }
+}
+
+/// Draw all players with their number of units in the top right corner of the window.
+pub fn render_players(
+    players: &[Player],
+    own_id: Option<ClientId>,
+    window_size: Vec2,
+    c: &Context,
+    cache: &mut GlyphCache<'_>,
+    gl: &mut GlGraphics,
+) {
+    const WIDTH: f64 = 160.0;
+    let left = window_size.x - MARGIN - WIDTH;
+    rectangle(
+        HUD_BACKGROUND,
+        [
+            left,
+            MARGIN,
+            WIDTH,
+            players.len() as f64 * LINE_HEIGHT + MARGIN,
+        ],
+        c.transform,
+        gl,
+    );
+    // Spectators and replays get the full state, players only what they see
+    let fog = players.iter().any(|player| Some(player.id) == own_id);
+    for (i, player) in players.iter().enumerate() {
+        let color = colors::PLAYERS[player.color % colors::PLAYERS.len()].primary;
+        let line = player_label(player, own_id, fog);
+        let transform = c
+            .transform
+            .trans(left + MARGIN / 2.0, MARGIN + (i + 1) as f64 * LINE_HEIGHT);
+        Text::new_color(color, FONT_SIZE)
+            .draw(&line, cache, &c.draw_state, transform, gl)
+            .unwrap();
+    }
+}
+
+/// Draw the game speed, running votes and, while the game is paused, an overlay that dims the
+/// whole window. `info` is shown above, e.g. whose view a spectator follows.
+pub fn render_status(
+    game: &GameState,
+    info: &[String],
+    window_size: Vec2,
+    c: &Context,
+    cache: &mut GlyphCache<'_>,
+    gl: &mut GlGraphics,
+) {
+    let mut lines = info.to_vec();
+    if game.paused {
+        rectangle(
+            PAUSE_OVERLAY,
+            [0.0, 0.0, window_size.x, window_size.y],
+            c.transform,
+            gl,
+        );
+        lines.push("Paused, press Space to resume".to_string());
+    }
+    if !game.pause_votes.is_empty() {
+        lines.push(format!(
+            "Votes to {}: {}/{}",
+            if game.paused { "resume" } else { "pause" },
+            game.pause_votes.len(),
+            game.players.len()
+        ));
+    }
+    if game.speed != 1.0 {
+        lines.push(format!("Speed: {}x", game.speed));
+    }
+    for (i, line) in lines.iter().enumerate() {
+        let width = cache.width(FONT_SIZE, line).unwrap_or(0.0);
+        let transform = c.transform.trans(
+            (window_size.x - width) / 2.0,
+            MARGIN + (i + 1) as f64 * LINE_HEIGHT,
+        );
+        Text::new_color(YELLOW, FONT_SIZE)
+            .draw(line, cache, &c.draw_state, transform, gl)
+            .unwrap();
+    }
+}
+
+/// Draw lines of debug information in the top left corner of the window.
+pub fn render_debug(
+    lines: &[String],
+    c: &Context,
+    cache: &mut GlyphCache<'_>,
+    gl: &mut GlGraphics,
+) {
+    let text = Text::new_color(WHITE, FONT_SIZE);
+    for (i, line) in lines.iter().enumerate() {
+        let transform = c
+            .transform
+            .trans(MARGIN, MARGIN + (i + 1) as f64 * LINE_HEIGHT);
+        text.draw(line, cache, &c.draw_state, transform, gl)
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shown_entries() {
+        //! Entries that don't fit are replaced by one showing how many are missing.
+        let width = 5.0 * PANEL_ENTRY_WIDTH;
+        assert_eq!(shown_entries(0, width), 0);
+        assert_eq!(shown_entries(5, width), 5);
+        assert_eq!(shown_entries(6, width), 4);
+        assert_eq!(shown_entries(6, width + PANEL_ENTRY_WIDTH / 2.0), 4);
+        // Narrow panels still show the number of units
+        assert_eq!(shown_entries(3, 0.0), 0);
+        assert_eq!(shown_entries(1, 0.0), 1);
+    }
+
+    #[test]
+    fn test_player_label() {
+        let mut player = Player::new(2);
+        player.units.push(Unit::new(1, Vec2::new(0.0, 0.0)));
+        assert_eq!(
+            player_label(&player, Some(ClientId(2)), true),
+            "Player 2 (you): 1"
+        );
+        assert_eq!(
+            player_label(&player, Some(ClientId(1)), true),
+            "Player 2: 1 visible"
+        );
+        assert_eq!(player_label(&player, None, false), "Player 2: 1");
+    }
+}
@@ -0,0 +1,207 @@
+//! Screen shown while players gather before the match starts.
+
+use graphics::{clear, rectangle, Text, Transformed};
+use opengl_graphics::{GlGraphics, GlyphCache};
+use piston::input::{Key, RenderArgs};
+
+use crate::chat::{ChatChannel, ChatMessage, MAX_CHAT_LENGTH};
+use crate::client::chat::label;
+use crate::colors::{self, BLACK, ORANGE, WHITE, YELLOW};
+use crate::lobby::{LobbyState, MatchSettings, MAX_TEAMS};
+use crate::network::Message;
+use crate::state::ClientId;
+
+const FONT_SIZE: u32 = 18;
+const LINE_HEIGHT: f64 = 26.0;
+const MARGIN: f64 = 20.0;
+
+/// Number of chat messages shown
+const CHAT_LINES: usize = 8;
+
+const HELP: [&str; 2] = [
+    "Tab: ready  Left/Right: color  PageUp/PageDown: team  Up/Down: units (host)",
+    "Enter: send chat  Esc: quit",
+];
+
+#[derive(Debug, Default)]
+pub struct Lobby {
+    /// Latest state received from the server
+    pub state: LobbyState,
+    /// Chat message being typed
+    input: String,
+}
+
+impl Lobby {
+    pub fn new() -> Lobby {
+        Lobby::default()
+    }
+
+    /// Handle a key press and return the message to send to the server, if any.
+    pub fn on_key(&mut self, key: Key, client_id: ClientId) -> Option<Message> {
+        let player = self.state.player(client_id)?;
+        match key {
+            Key::Tab => Some(Message::Ready(!player.ready)),
+            Key::Left | Key::Right => {
+                let count = colors::PLAYERS.len();
+                let step = if key == Key::Right { 1 } else { count - 1 };
+                // Skip the colors other players use
+                (1..count)
+                    .map(|i| (player.color + i * step) % count)
+                    .find(|color| self.state.players.iter().all(|p| p.color != *color))
+                    .map(Message::ChooseColor)
+            }
+            Key::Up | Key::Down if self.state.host == Some(client_id) => {
+                let units = self.state.settings.units_per_player;
+                let units = if key == Key::Up {
+                    units + 1
+                } else {
+                    units.saturating_sub(1)
+                };
+                Some(Message::ChangeSettings(MatchSettings {
+                    units_per_player: units,
+                }))
+            }
+            Key::PageUp => Some(Message::ChooseTeam((player.team + 1) % MAX_TEAMS)),
+            Key::PageDown => Some(Message::ChooseTeam(
+                (player.team + MAX_TEAMS - 1) % MAX_TEAMS,
+            )),
+            Key::Backspace => {
+                self.input.pop();
+                None
+            }
+            Key::Return if !self.input.trim().is_empty() => Some(Message::Chat(
+                ChatChannel::All,
+                std::mem::take(&mut self.input),
+            )),
+            _ => None,
+        }
+    }
+
+    pub fn on_text(&mut self, text: &str) {
+        let room = MAX_CHAT_LENGTH.saturating_sub(self.input.chars().count());
+        self.input
+            .extend(text.chars().filter(|c| !c.is_control()).take(room));
+    }
+
+    pub fn render<'a, I: Iterator<Item = &'a ChatMessage>>(
+        &self,
+        args: &RenderArgs,
+        client_id: Option<ClientId>,
+        chat: I,
+        gl: &mut GlGraphics,
+        cache: &mut GlyphCache<'_>,
+    ) {
+        let text = Text::new_color(YELLOW, FONT_SIZE);
+        let highlight = Text::new_color(ORANGE, FONT_SIZE);
+        let state = &self.state;
+        gl.draw(args.viewport(), |c, gl| {
+            clear(BLACK, gl);
+            let mut y = MARGIN;
+            let mut line = |label: &str, text: &Text, gl: &mut GlGraphics, y: &mut f64| {
+                *y += LINE_HEIGHT;
+                text.draw(
+                    label,
+                    cache,
+                    &c.draw_state,
+                    c.transform.trans(MARGIN + 24.0, *y),
+                    gl,
+                )
+                .unwrap();
+            };
+
+            line("Lobby", &highlight, gl, &mut y);
+            for player in state.players.iter() {
+                let mut entry = format!("Player {} (team {})", player.id, player.team + 1);
+                if state.host == Some(player.id) {
+                    entry.push_str(" (host)");
+                }
+                if Some(player.id) == client_id {
+                    entry.push_str(" (you)");
+                }
+                entry.push_str(if player.ready {
+                    ": ready"
+                } else {
+                    ": not ready"
+                });
+                let color = colors::PLAYERS[player.color % colors::PLAYERS.len()].primary;
+                rectangle(
+                    color,
+                    [MARGIN, y + LINE_HEIGHT - 16.0, 16.0, 16.0],
+                    c.transform,
+                    gl,
+                );
+                line(&entry, &text, gl, &mut y);
+            }
+
+            y += LINE_HEIGHT;
+            let settings = format!("Units per player: {}", state.settings.units_per_player);
+            line(&settings, &text, gl, &mut y);
+
+            y += LINE_HEIGHT;
+            let chat_text = Text::new_color(WHITE, FONT_SIZE);
+            let messages: Vec<&ChatMessage> = chat.collect();
+            let skip = messages.len().saturating_sub(CHAT_LINES);
+            for message in messages.into_iter().skip(skip) {
+                line(&label(message, client_id), &chat_text, gl, &mut y);
+            }
+            line(&format!("> {}_", self.input), &highlight, gl, &mut y);
+
+            y += LINE_HEIGHT;
+            for help in HELP.iter() {
+                line(help, &text, gl, &mut y);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lobby() -> Lobby {
+        let mut lobby = Lobby::new();
+        lobby.state.join(ClientId(1));
+        lobby.state.join(ClientId(2));
+        lobby
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut lobby = lobby();
+        assert_eq!(
+            lobby.on_key(Key::Tab, ClientId(2)),
+            Some(Message::Ready(true))
+        );
+        // Color 0 belongs to the other player
+        assert_eq!(
+            lobby.on_key(Key::Left, ClientId(2)),
+            Some(Message::ChooseColor(colors::PLAYERS.len() - 1))
+        );
+        assert_eq!(
+            lobby.on_key(Key::Right, ClientId(1)),
+            Some(Message::ChooseColor(2))
+        );
+
+        // Only the host changes settings
+        assert_eq!(lobby.on_key(Key::Up, ClientId(2)), None);
+        assert_eq!(
+            lobby.on_key(Key::Up, ClientId(1)),
+            Some(Message::ChangeSettings(MatchSettings {
+                units_per_player: 5
+            }))
+        );
+    }
+
+    #[test]
+    fn test_chat_input() {
+        let mut lobby = lobby();
+        assert_eq!(lobby.on_key(Key::Return, ClientId(1)), None);
+        lobby.on_text("hi!");
+        lobby.on_key(Key::Backspace, ClientId(1));
+        assert_eq!(
+            lobby.on_key(Key::Return, ClientId(1)),
+            Some(Message::Chat(ChatChannel::All, "hi".to_string()))
+        );
+        assert_eq!(lobby.on_key(Key::Return, ClientId(1)), None);
+    }
+}
@@ -1,70 +1,391 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
 use opengl_graphics::GlGraphics;
 use opengl_graphics::GlyphCache;
 
-use piston::input::RenderArgs;
+use piston::input::{Key, RenderArgs};
 
+use crate::client::config::{Config, ServerAddress};
 use crate::colors::{BLACK, ORANGE, YELLOW};
+use crate::discovery::{self, DiscoveredServer};
+use crate::state::ClientId;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Default)]
+const FONT_SIZE: u32 = 32;
+const LINE_HEIGHT: f64 = 48.0;
+
+/// Time to wait for answers to a discovery probe
+const DISCOVERY_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Entries {
-    #[default]
+    Host,
+    Port,
     Start,
+    Reconnect,
+    /// Watch the match without playing
+    Spectate,
+    /// Entry of the recent servers list
+    Recent(usize),
+    /// Look for servers in the local network
+    Search,
+    /// Entry of the servers found in the local network
+    Discovered(usize),
+    Settings,
     Exit,
+    EdgePan,
+    HealthBars,
+    Back,
 }
 
-impl Entries {
-    pub fn next(&mut self) {
-        *self = match *self {
-            Entries::Start => Entries::Exit,
-            Entries::Exit => Entries::Start,
-        };
-    }
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Default)]
+enum Screen {
+    #[default]
+    Main,
+    Settings,
 }
 
-#[derive(Clone, Copy, Debug, Default)]
+/// What the application should do after a key press in the menu.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Connect(ServerAddress, Option<ClientId>),
+    Spectate(ServerAddress),
+    /// The server address can't be used, e.g. because the port is not a number
+    Invalid(String),
+    Exit,
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Menu {
-    selected_entry: Entries,
+    screen: Screen,
+    selected: usize,
+    host: String,
+    port: String,
+    /// Servers found by the last search in the local network
+    discovered: Arc<Mutex<Vec<DiscoveredServer>>>,
+    searching: Arc<AtomicBool>,
+    /// Whether a search was started since the menu was created
+    searched: bool,
 }
 
 impl Menu {
-    pub fn new() -> Menu {
+    pub fn new(config: &Config) -> Menu {
         Menu {
-            selected_entry: Entries::Start,
+            screen: Screen::Main,
+            selected: 0,
+            host: config.server.host.clone(),
+            port: config.server.port.to_string(),
+            discovered: Arc::new(Mutex::new(vec![])),
+            searching: Arc::new(AtomicBool::new(false)),
+            searched: false,
+        }
+    }
+
+    /// Start the first search when the menu is shown, later ones are up to the player.
+    pub fn search_once(&mut self) {
+        if !self.searched {
+            self.search();
+        }
+    }
+
+    /// Look for servers in the local network in the background.
+    pub fn search(&mut self) {
+        self.searched = true;
+        if self.searching.swap(true, Ordering::SeqCst) {
+            return;
         }
+        let discovered = self.discovered.clone();
+        let searching = self.searching.clone();
+        thread::spawn(move || {
+            match discovery::discover_lan(DISCOVERY_TIMEOUT) {
+                Ok(servers) => *discovered.lock().unwrap() = servers,
+                Err(e) => error!("Discovery failed: {}", e),
+            }
+            searching.store(false, Ordering::SeqCst);
+        });
     }
 
-    pub fn render(&self, args: &RenderArgs, gl: &mut GlGraphics, cache: &mut GlyphCache<'_>) {
+    /// Return the entries of the current screen.
+    pub fn entries(&self, config: &Config) -> Vec<Entries> {
+        match self.screen {
+            Screen::Main => {
+                let mut entries = vec![Entries::Host, Entries::Port, Entries::Start];
+                if self.client_id(config).is_some() {
+                    entries.push(Entries::Reconnect);
+                }
+                entries.push(Entries::Spectate);
+                entries.extend((0..config.recent_servers.len()).map(Entries::Recent));
+                entries.push(Entries::Search);
+                let discovered = self.discovered.lock().unwrap().len();
+                entries.extend((0..discovered).map(Entries::Discovered));
+                entries.push(Entries::Settings);
+                entries.push(Entries::Exit);
+                entries
+            }
+            Screen::Settings => vec![Entries::EdgePan, Entries::HealthBars, Entries::Back],
+        }
+    }
+
+    fn label(&self, entry: Entries, config: &Config, selected: bool) -> String {
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        let cursor = if selected { "_" } else { "" };
+        match entry {
+            Entries::Host => format!("Host: {}{}", self.host, cursor),
+            Entries::Port => format!("Port: {}{}", self.port, cursor),
+            Entries::Start => "Start".to_string(),
+            Entries::Reconnect => match self.client_id(config) {
+                Some(id) => format!("Reconnect as player {}", id),
+                None => "Reconnect".to_string(),
+            },
+            Entries::Spectate => "Spectate".to_string(),
+            Entries::Recent(i) => {
+                let server = &config.recent_servers[i];
+                format!("Recent: {}:{}", server.host, server.port)
+            }
+            Entries::Search => {
+                if self.searching.load(Ordering::SeqCst) {
+                    "Searching LAN...".to_string()
+                } else {
+                    "Search LAN".to_string()
+                }
+            }
+            Entries::Discovered(i) => match self.discovered.lock().unwrap().get(i) {
+                Some(server) => format!(
+                    "LAN: {} ({}, {} players, {:?})",
+                    server.info.name, server.addr, server.info.players, server.info.state
+                ),
+                None => String::new(),
+            },
+            Entries::Settings => "Settings".to_string(),
+            Entries::Exit => "Exit".to_string(),
+            Entries::EdgePan => format!("Edge scrolling: {}", on_off(config.settings.edge_pan)),
+            Entries::HealthBars => format!("Health bars: {}", on_off(config.settings.health_bars)),
+            Entries::Back => "Back".to_string(),
+        }
+    }
+
+    pub fn render(
+        &self,
+        args: &RenderArgs,
+        config: &Config,
+        gl: &mut GlGraphics,
+        cache: &mut GlyphCache<'_>,
+    ) {
         use graphics::{clear, Text, Transformed};
-        let text = Text::new_color(YELLOW, 64);
-        let text_selected = Text::new_color(ORANGE, 64);
+        let text = Text::new_color(YELLOW, FONT_SIZE);
+        let text_selected = Text::new_color(ORANGE, FONT_SIZE);
         gl.draw(args.viewport(), |c, gl| {
             // Clear the screen.
             clear(BLACK, gl);
-            let mut transform = c.transform;
+            let mut transform = c.transform.trans(20.0, 0.0);
 
-            for entry in &[Entries::Start, Entries::Exit] {
-                transform = transform.trans(0.0, 100.0);
-                if *entry == self.selected_entry {
+            for (i, entry) in self.entries(config).into_iter().enumerate() {
+                transform = transform.trans(0.0, LINE_HEIGHT);
+                let selected = i == self.selected;
+                let label = self.label(entry, config, selected);
+                if selected {
                     text_selected
-                        .draw(&format!("{:?}", entry), cache, &c.draw_state, transform, gl)
+                        .draw(&label, cache, &c.draw_state, transform, gl)
                         .unwrap();
                 } else {
-                    text.draw(&format!("{:?}", entry), cache, &c.draw_state, transform, gl)
+                    text.draw(&label, cache, &c.draw_state, transform, gl)
                         .unwrap();
                 }
             }
         });
     }
 
-    pub fn get_selected_entry(&self) -> Entries {
-        self.selected_entry
+    pub fn get_selected_entry(&self, config: &Config) -> Entries {
+        let entries = self.entries(config);
+        entries[self.selected.min(entries.len() - 1)]
+    }
+
+    pub fn previous(&mut self, config: &Config) {
+        let count = self.entries(config).len();
+        self.selected = (self.selected + count - 1) % count;
+    }
+
+    pub fn next(&mut self, config: &Config) {
+        let count = self.entries(config).len();
+        self.selected = (self.selected + 1) % count;
+    }
+
+    /// Return the server address entered in the text fields.
+    fn server(&self) -> Result<ServerAddress, String> {
+        let port = self
+            .port
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid port: {}", self.port))?;
+        Ok(ServerAddress::new(self.host.trim(), port))
+    }
+
+    /// Return the ID to reconnect to the server in the host and port fields with, if any.
+    fn client_id(&self, config: &Config) -> Option<ClientId> {
+        self.server()
+            .ok()
+            .and_then(|server| config.client_id(&server))
+    }
+
+    /// Handle a key press, changing the settings in `config`.
+    pub fn on_key(&mut self, key: Key, config: &mut Config) -> Option<Action> {
+        match key {
+            Key::Up => self.previous(config),
+            Key::Down | Key::Tab => self.next(config),
+            Key::Backspace => match self.get_selected_entry(config) {
+                Entries::Host => {
+                    self.host.pop();
+                }
+                Entries::Port => {
+                    self.port.pop();
+                }
+                _ => {}
+            },
+            Key::Return => return self.activate(config),
+            _ => {}
+        }
+        None
+    }
+
+    fn activate(&mut self, config: &mut Config) -> Option<Action> {
+        match self.get_selected_entry(config) {
+            Entries::Host | Entries::Port => self.next(config),
+            Entries::Start => {
+                return Some(match self.server() {
+                    Ok(server) => Action::Connect(server, None),
+                    Err(message) => Action::Invalid(message),
+                })
+            }
+            Entries::Reconnect => {
+                return Some(match self.server() {
+                    Ok(server) => {
+                        let id = config.client_id(&server);
+                        Action::Connect(server, id)
+                    }
+                    Err(message) => Action::Invalid(message),
+                })
+            }
+            Entries::Spectate => {
+                return Some(match self.server() {
+                    Ok(server) => Action::Spectate(server),
+                    Err(message) => Action::Invalid(message),
+                })
+            }
+            Entries::Recent(i) => {
+                let server = config.recent_servers[i].clone();
+                self.host = server.host.clone();
+                self.port = server.port.to_string();
+                return Some(Action::Connect(server, None));
+            }
+            Entries::Search => self.search(),
+            Entries::Discovered(i) => {
+                let server = self.discovered.lock().unwrap().get(i).cloned()?;
+                let server = ServerAddress::new(server.addr.ip().to_string(), server.addr.port());
+                self.host = server.host.clone();
+                self.port = server.port.to_string();
+                return Some(Action::Connect(server, None));
+            }
+            Entries::Settings => {
+                self.screen = Screen::Settings;
+                self.selected = 0;
+            }
+            Entries::Exit => return Some(Action::Exit),
+            Entries::EdgePan => config.settings.edge_pan = !config.settings.edge_pan,
+            Entries::HealthBars => config.settings.health_bars = !config.settings.health_bars,
+            Entries::Back => {
+                self.screen = Screen::Main;
+                self.selected = 0;
+            }
+        }
+        None
+    }
+
+    /// Handle text typed by the user, entered into the selected text field.
+    pub fn on_text(&mut self, text: &str, config: &Config) {
+        let text = text.chars().filter(|c| !c.is_control());
+        match self.get_selected_entry(config) {
+            Entries::Host => self.host.extend(text),
+            Entries::Port => self.port.extend(text.filter(|c| c.is_ascii_digit())),
+            _ => {}
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_fields() {
+        //! Typed text goes to the selected field, the port only accepts digits.
+        let mut config = Config::default();
+        let mut menu = Menu::new(&config);
+        menu.on_key(Key::Backspace, &mut config);
+        menu.on_text("2", &config);
+        menu.on_key(Key::Down, &mut config);
+        menu.on_key(Key::Backspace, &mut config);
+        menu.on_text("a1", &config);
+        assert_eq!(menu.on_key(Key::Down, &mut config), None);
+        assert_eq!(
+            menu.on_key(Key::Return, &mut config),
+            Some(Action::Connect(ServerAddress::new("127.0.0.2", 8081), None))
+        );
 
-    pub fn previous(&mut self) {
-        self.selected_entry.next();
+        // An empty port can't be used
+        menu.on_key(Key::Up, &mut config);
+        for _ in 0..4 {
+            menu.on_key(Key::Backspace, &mut config);
+        }
+        menu.on_key(Key::Down, &mut config);
+        assert_eq!(
+            menu.on_key(Key::Return, &mut config),
+            Some(Action::Invalid("Invalid port: ".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_reconnect() {
+        //! The reconnect entry only exists with an ID saved for the server and uses it.
+        let mut config = Config::default();
+        let menu = Menu::new(&config);
+        assert!(!menu.entries(&config).contains(&Entries::Reconnect));
+
+        config.set_client_id(&ServerAddress::new("127.0.0.1", 8080), ClientId(4));
+        let mut menu = Menu::new(&config);
+        for _ in 0..3 {
+            menu.on_key(Key::Down, &mut config);
+        }
+        assert_eq!(menu.get_selected_entry(&config), Entries::Reconnect);
+        assert_eq!(
+            menu.on_key(Key::Return, &mut config),
+            Some(Action::Connect(
+                ServerAddress::new("127.0.0.1", 8080),
+                Some(ClientId(4))
+            ))
+        );
+
+        menu.on_key(Key::Down, &mut config);
+        assert_eq!(
+            menu.on_key(Key::Return, &mut config),
+            Some(Action::Spectate(ServerAddress::new("127.0.0.1", 8080)))
+        );
+
+        menu.port = "8081".to_string();
+        assert!(!menu.entries(&config).contains(&Entries::Reconnect));
     }
 
-    pub fn next(&mut self) {
-        self.selected_entry.next();
+    #[test]
+    fn test_settings() {
+        let mut config = Config::default();
+        let mut menu = Menu::new(&config);
+        while menu.get_selected_entry(&config) != Entries::Settings {
+            menu.on_key(Key::Down, &mut config);
+        }
+        menu.on_key(Key::Return, &mut config);
+        assert_eq!(menu.get_selected_entry(&config), Entries::EdgePan);
+        menu.on_key(Key::Return, &mut config);
+        assert!(!config.settings.edge_pan);
     }
 }
@@ -0,0 +1,137 @@
+//! Overview map of the whole world in a corner of the screen.
+
+use graphics::math::Matrix2d;
+use graphics::{ellipse, rectangle, Graphics, Rectangle, Transformed};
+
+use crate::colors::{self, MINIMAP_BACKGROUND, MINIMAP_VIEWPORT, ORANGE};
+use crate::common::Vec2;
+use crate::state::{GameState, WorldState};
+
+use super::fog::Fog;
+
+/// Length of the longer side of the minimap in pixels
+const SIZE: f64 = 200.0;
+
+/// Distance of the minimap from the window border in pixels
+const MARGIN: f64 = 10.0;
+
+/// Radius of the dots representing units in pixels
+const DOT_RADIUS: f64 = 2.0;
+
+/// Placement of the minimap in the bottom left corner of the window.
+pub struct Minimap {
+    /// Screen position of the top left corner
+    origin: Vec2,
+    /// Pixels per world unit
+    scale: f64,
+    world: WorldState,
+}
+
+impl Minimap {
+    pub fn new(world: &WorldState, window_size: Vec2) -> Minimap {
+        let scale = SIZE / world.x.max(world.y);
+        Minimap {
+            origin: Vec2::new(MARGIN, window_size.y - MARGIN - world.y * scale),
+            scale,
+            world: world.clone(),
+        }
+    }
+
+    /// Return the screen x coordinate of the right border of the minimap.
+    pub fn right(&self) -> f64 {
+        self.origin.x + self.world.x * self.scale
+    }
+
+    /// Convert a world position to a position on the screen.
+    pub fn to_screen(&self, position: Vec2) -> Vec2 {
+        self.origin + position * self.scale
+    }
+
+    /// Return the world position shown at the given screen position, if it is on the minimap.
+    pub fn to_world(&self, position: Vec2) -> Option<Vec2> {
+        let world = (position - self.origin) / self.scale;
+        if world.x >= 0.0 && world.y >= 0.0 && world.x <= self.world.x && world.y <= self.world.y {
+            Some(world)
+        } else {
+            None
+        }
+    }
+
+    /// Draw the world border, all units, the fog of war and the visible area given by two world
+    /// positions.
+    pub fn render<G: Graphics>(
+        &self,
+        game_state: &GameState,
+        fog: Option<&Fog>,
+        visible_area: (Vec2, Vec2),
+        transform: Matrix2d,
+        gl: &mut G,
+    ) {
+        let size = Vec2::new(self.world.x, self.world.y) * self.scale;
+        let bounds = [self.origin.x, self.origin.y, size.x, size.y];
+        rectangle(MINIMAP_BACKGROUND, bounds, transform, gl);
+        Rectangle::new_border(ORANGE, 1.0).draw(bounds, &Default::default(), transform, gl);
+
+        for player in game_state.players.iter() {
+            let color = colors::PLAYERS[player.color % colors::PLAYERS.len()].primary;
+            for unit in player.units.iter() {
+                let p = self.to_screen(unit.position);
+                let dot = [
+                    p.x - DOT_RADIUS,
+                    p.y - DOT_RADIUS,
+                    2.0 * DOT_RADIUS,
+                    2.0 * DOT_RADIUS,
+                ];
+                ellipse(color, dot, transform, gl);
+            }
+        }
+        if let Some(fog) = fog {
+            let world_transform = transform
+                .trans(self.origin.x, self.origin.y)
+                .scale(self.scale, self.scale);
+            fog.render(world_transform, gl);
+        }
+
+        // Only draw the part of the visible area that lies inside the world
+        let (a, b) = (
+            self.to_screen(self.world.clamp(visible_area.0)),
+            self.to_screen(self.world.clamp(visible_area.1)),
+        );
+        Rectangle::new_border(MINIMAP_VIEWPORT, 1.0).draw(
+            [a.x, a.y, b.x - a.x, b.y - a.y],
+            &Default::default(),
+            transform,
+            gl,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coordinates() {
+        //! Screen and world positions on the minimap convert into each other.
+        let world = WorldState::new(800.0, 600.0);
+        let minimap = Minimap::new(&world, Vec2::new(1000.0, 700.0));
+
+        // The minimap sits in the bottom left corner and keeps the world's aspect ratio
+        assert_eq!(
+            minimap.to_screen(Vec2::new(0.0, 0.0)),
+            Vec2::new(10.0, 540.0)
+        );
+        assert_eq!(
+            minimap.to_screen(Vec2::new(800.0, 600.0)),
+            Vec2::new(210.0, 690.0)
+        );
+
+        let position = Vec2::new(400.0, 100.0);
+        assert_eq!(
+            minimap.to_world(minimap.to_screen(position)),
+            Some(position)
+        );
+        assert_eq!(minimap.to_world(Vec2::new(500.0, 600.0)), None);
+        assert_eq!(minimap.to_world(Vec2::new(5.0, 600.0)), None);
+    }
+}
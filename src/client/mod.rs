@@ -1,53 +1,106 @@
 use std::collections::VecDeque;
 use std::error::Error;
 use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time;
+use std::time::{self, Duration, Instant};
 
 use bincode::Options;
+use cgmath::InnerSpace;
 use opengl_graphics::GlGraphics;
 use opengl_graphics::GlyphCache;
 use piston::input::{Button, Key, MouseButton, RenderArgs, UpdateArgs};
 
-use crate::colors::{self, BLACK, ORANGE, TRANSPARENT_WHITE, WHITE};
+use crate::chat::ChatMessage;
+use crate::colors::{self, BLACK, HOVER, ORANGE, SELECTION_BOX, TRANSPARENT_WHITE, WHITE};
 use crate::common::Vec2;
-use crate::network::{Command, Message};
+use crate::formation::Formation;
+use crate::lobby::LobbyState;
+use crate::network::{Command, Message, MAX_MESSAGE_SIZE};
+use crate::replay::Replay;
 use crate::shapes::Shape;
-use crate::state::{ClientId, GameState, UnitId, WorldState, UNIT_SIZE};
+use crate::state::{ClientId, GameState, Order, Unit, UnitId, WorldState, UNIT_SIZE};
 
+pub mod camera;
+pub mod chat;
+pub mod config;
+pub mod control_groups;
 pub mod error;
+pub mod fog;
+pub mod hud;
+pub mod lobby;
 pub mod menu;
+pub mod minimap;
+pub mod replay;
+pub mod selection;
+pub mod stats;
 
+use self::camera::Camera;
+use self::chat::ChatBox;
+use self::config::{Config, ServerAddress};
+use self::control_groups::ControlGroups;
+use self::fog::Fog;
+use self::lobby::Lobby;
 use self::menu::Menu;
+use self::minimap::Minimap;
+use self::replay::ReplayViewer;
+use self::selection::Selection;
+use self::stats::NetworkStats;
+
+/// Distance in pixels of the chat's input line from the bottom of the window, above the minimap
+const CHAT_BOTTOM_OFFSET: f64 = 190.0;
+
+/// Time between two latency measurements
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Distance in pixels the camera moves per arrow key press
+const KEY_PAN_DISTANCE: f64 = 10.0;
+
+/// Distance in pixels the mouse has to move with the button held down to start a selection box
+const DRAG_THRESHOLD: f64 = 5.0;
 
 pub struct NetworkClient {
     pub game_state: Arc<Mutex<Option<GameState>>>,
     server_addr: SocketAddr,
     stream: Option<TcpStream>,
-    commands: Arc<Mutex<VecDeque<Command>>>,
+    outgoing: Arc<Mutex<VecDeque<Message>>>,
+    lobby: Arc<Mutex<Option<LobbyState>>>,
+    chat: Arc<Mutex<Vec<ChatMessage>>>,
+    stats: Arc<Mutex<NetworkStats>>,
 }
 
 impl NetworkClient {
     pub fn new<T: ToSocketAddrs>(
         server_addrs: T,
         game_state: Arc<Mutex<Option<GameState>>>,
-        commands: Arc<Mutex<VecDeque<Command>>>,
-    ) -> NetworkClient {
-        let server_addr = server_addrs.to_socket_addrs().unwrap().next().unwrap();
-        NetworkClient {
+        outgoing: Arc<Mutex<VecDeque<Message>>>,
+        lobby: Arc<Mutex<Option<LobbyState>>>,
+        chat: Arc<Mutex<Vec<ChatMessage>>>,
+        stats: Arc<Mutex<NetworkStats>>,
+    ) -> Result<NetworkClient, Box<dyn Error>> {
+        let server_addr = server_addrs
+            .to_socket_addrs()?
+            .next()
+            .ok_or("Could not resolve server address")?;
+        Ok(NetworkClient {
             game_state,
             server_addr,
             stream: None,
-            commands,
-        }
+            outgoing,
+            lobby,
+            chat,
+            stats,
+        })
     }
 
-    pub fn connect(&mut self) -> Result<(ClientId, WorldState), Box<dyn Error>> {
+    /// Connect to the server with the given hello message, i.e. as a new player, as an existing
+    /// player or as a spectator.
+    pub fn connect(&mut self, hello: Message) -> Result<(ClientId, WorldState), Box<dyn Error>> {
         let mut stream = TcpStream::connect(self.server_addr)?;
-        let bincode = bincode::DefaultOptions::new().with_limit(1024);
-        info!("Sending client hello");
-        bincode.serialize_into(&mut stream, &Message::ClientHello)?;
+        let bincode = bincode::DefaultOptions::new().with_limit(MAX_MESSAGE_SIZE);
+        info!("Sending {:?}", hello);
+        bincode.serialize_into(&mut stream, &hello)?;
         let server_hello = bincode.deserialize_from(&mut stream);
 
         self.stream = Some(stream);
@@ -61,38 +114,66 @@ impl NetworkClient {
     pub fn update(&self) {
         let stream = self.stream.as_ref().expect("Stream not here :(");
         let mut command_stream = stream.try_clone().unwrap();
-        let commands = self.commands.clone();
-        let bincode = bincode::DefaultOptions::new().with_limit(1024);
+        let outgoing = self.outgoing.clone();
+        let bincode = bincode::DefaultOptions::new().with_limit(MAX_MESSAGE_SIZE);
+        // Ping timestamps are measured in microseconds since the start of the connection
+        let start = Instant::now();
 
-        // Command sender loop
+        // Message sender loop
         thread::spawn(move || {
+            let mut last_ping: Option<Instant> = None;
             loop {
-                let command = {
-                    let mut commands = commands.lock().unwrap();
-                    commands.pop_front()
-                };
-                if let Some(cmd) = command {
-                    info!("Got command: {:?}", cmd);
-                    //let cmd = Message::Command(Command::Move(0.into(), [100f64, 100f64]));
+                if last_ping.is_none_or(|time| time.elapsed() >= PING_INTERVAL) {
+                    last_ping = Some(Instant::now());
+                    let timestamp = start.elapsed().as_micros() as u64;
                     bincode
-                        .serialize_into(&mut command_stream, &Message::Command(cmd))
-                        .unwrap_or_else(|e| error!("Sending command failed: {}", e));
+                        .serialize_into(&mut command_stream, &Message::Ping(timestamp))
+                        .unwrap_or_else(|e| error!("Sending ping failed: {}", e));
+                }
+                let message = {
+                    let mut outgoing = outgoing.lock().unwrap();
+                    outgoing.pop_front()
+                };
+                match message {
+                    Some(message) => {
+                        info!("Sending message: {:?}", message);
+                        bincode
+                            .serialize_into(&mut command_stream, &message)
+                            .unwrap_or_else(|e| error!("Sending message failed: {}", e));
+                    }
+                    // Only wait while there is nothing to send
+                    None => thread::sleep(time::Duration::from_millis(10)),
                 }
-                thread::sleep(time::Duration::from_millis(10));
             }
         });
 
         let mut game_state_stream = stream.try_clone().unwrap();
         let game_state = self.game_state.clone();
+        let stats = self.stats.clone();
+        let lobby = self.lobby.clone();
+        let chat = self.chat.clone();
         thread::spawn(move || loop {
-            let game_state_server: Result<GameState, _> =
-                bincode.deserialize_from(&mut game_state_stream);
-            match game_state_server {
-                Ok(game) => {
+            let message: Result<Message, _> = bincode.deserialize_from(&mut game_state_stream);
+            match message {
+                Ok(Message::UpdateGamestate(game)) => {
                     debug!("{:?}", game);
+                    stats.lock().unwrap().on_snapshot(Instant::now(), game.tick);
                     let mut game_state_lock = game_state.lock().unwrap();
                     *game_state_lock = Some(game);
                 }
+                Ok(Message::LobbyUpdate(state)) => {
+                    *lobby.lock().unwrap() = Some(state);
+                }
+                Ok(Message::ChatMessage(message)) => {
+                    chat.lock().unwrap().push(message);
+                }
+                Ok(Message::Pong(timestamp)) => {
+                    let rtt = (start.elapsed().as_micros() as u64).saturating_sub(timestamp);
+                    stats.lock().unwrap().on_pong(rtt as f64 / 1000.0);
+                }
+                Ok(message) => {
+                    error!("Did receive unexpected message: {:?}", message);
+                }
                 Err(e) => {
                     error!("{:?}", e);
                     thread::sleep(time::Duration::from_millis(200));
@@ -106,6 +187,8 @@ impl NetworkClient {
 pub enum State {
     Menu,
     Error(error::Message),
+    /// Connected, waiting for the match to start
+    Lobby,
     Running,
 }
 
@@ -114,86 +197,312 @@ pub struct App {
     pub world_state: Option<WorldState>,
     pub game_state_server: Arc<Mutex<Option<GameState>>>,
     pub game_state: GameState,
-    pub selected_units: Vec<UnitId>,
-    pub commands: Arc<Mutex<VecDeque<Command>>>,
+    pub selection: Selection,
+    /// Messages waiting to be sent to the server
+    pub outgoing: Arc<Mutex<VecDeque<Message>>>,
+    pub lobby_server: Arc<Mutex<Option<LobbyState>>>,
+    /// Chat messages received since the last update
+    pub chat_server: Arc<Mutex<Vec<ChatMessage>>>,
+    pub network_stats: Arc<Mutex<NetworkStats>>,
     pub cursor: Vec2,
     pub state: State,
-    zoom: f64,
-    scroll: Vec2,
+    camera: Camera,
     menu: Menu,
+    lobby: Lobby,
+    chat: ChatBox,
+    /// Explored and visible parts of the world, once the world is known
+    fog: Option<Fog>,
     client_id: Option<ClientId>,
-    server_ip: String,
-    server_port: u16,
+    /// Whether the client watches the match instead of playing
+    spectating: bool,
+    /// Player whose view a spectator follows, `None` shows everything
+    viewpoint: Option<ClientId>,
+    /// Recorded match being played back instead of a connection to a server
+    replay: Option<ReplayViewer>,
+    /// Settings and connection history, saved when they change
+    config: Config,
     debug: bool,
+    /// Whether a shift key is currently held down
+    shift: bool,
+    /// Formation used when moving a group of units
+    formation: Formation,
+    /// Screen position where the left mouse button was pressed, while it is held down
+    drag_start: Option<Vec2>,
+    /// Whether a control key is currently held down
+    ctrl: bool,
+    /// Stored selections, recalled with the number keys
+    control_groups: ControlGroups,
+    /// Whether the left mouse button was pressed on the minimap and is still held down
+    minimap_drag: bool,
+    /// Time of the last rendered frame and the smoothed frame rate, shown in debug mode
+    last_frame: Option<Instant>,
+    fps: f64,
 }
 
 impl App {
-    pub fn new(gl: GlGraphics, server_ip: String, server_port: u16) -> App {
+    pub fn new(gl: GlGraphics, config: Config) -> App {
+        let menu = Menu::new(&config);
         App {
             gl,
             world_state: None,
             game_state_server: Arc::new(Mutex::new(None)),
             game_state: GameState::new(),
-            selected_units: vec![],
-            commands: Arc::new(Mutex::new(VecDeque::new())),
+            selection: Selection::new(),
+            outgoing: Arc::new(Mutex::new(VecDeque::new())),
+            lobby_server: Arc::new(Mutex::new(None)),
+            chat_server: Arc::new(Mutex::new(vec![])),
+            chat: ChatBox::new(),
+            fog: None,
+            lobby: Lobby::new(),
+            network_stats: Arc::new(Mutex::new(NetworkStats::new())),
             cursor: Vec2::new(0.0, 0.0),
             state: State::Menu,
-            zoom: 1.0,
-            scroll: Vec2::new(0.0, 0.0),
-            menu: Menu::new(),
+            camera: Camera::new(),
+            menu,
             client_id: None,
-            server_ip,
-            server_port,
+            spectating: false,
+            viewpoint: None,
+            replay: None,
+            config,
             debug: false,
+            shift: false,
+            formation: Formation::default(),
+            drag_start: None,
+            ctrl: false,
+            control_groups: ControlGroups::new(),
+            minimap_drag: false,
+            last_frame: None,
+            fps: 0.0,
         }
     }
 
-    pub fn start(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Connect to the server with the given hello message.
+    pub fn start(&mut self, server: ServerAddress, hello: Message) -> Result<(), Box<dyn Error>> {
+        let spectating = hello == Message::SpectatorHello;
         let mut network_client = NetworkClient::new(
-            (&*self.server_ip, self.server_port),
+            (&*server.host, server.port),
             self.game_state_server.clone(),
-            self.commands.clone(),
-        );
-        let (client_id, world_state) = network_client.connect()?;
+            self.outgoing.clone(),
+            self.lobby_server.clone(),
+            self.chat_server.clone(),
+            self.network_stats.clone(),
+        )?;
+        let (client_id, world_state) = network_client.connect(hello)?;
         self.client_id = Some(client_id);
+        self.spectating = spectating;
+        self.viewpoint = None;
+
+        // Remember the server and the player to reconnect later on
+        if !spectating {
+            self.config.set_client_id(&server, client_id);
+        }
+        self.config.server = server.clone();
+        self.config.add_recent_server(server);
+        self.save_config();
+
+        self.camera.set_world(&world_state);
+        self.camera
+            .center_on(Vec2::new(world_state.x, world_state.y) / 2.0);
+        self.fog = Some(Fog::new(&world_state));
         self.world_state = Some(world_state);
         network_client.update();
         Ok(())
     }
 
-    pub fn select(&mut self, position: Vec2) {
-        let player = {
-            let index = self.client_id.unwrap_or(ClientId(0)).0 as usize;
-            self.game_state.players.get(index)
+    /// Connect to the server and wait in its lobby, or show why that failed.
+    fn join(&mut self, server: ServerAddress, hello: Message) {
+        match self.start(server, hello) {
+            Ok(_) => {
+                self.state = State::Lobby;
+            }
+            Err(err) => {
+                self.state = State::Error(error::Message::new(err.to_string()));
+            }
+        }
+    }
+
+    /// Play the replay file back, watching it like a spectator.
+    pub fn play_replay<P: AsRef<Path>>(&mut self, path: P) {
+        let replay = match Replay::load(path) {
+            Ok(replay) => replay,
+            Err(err) => {
+                let message = format!("Could not load replay: {}", err);
+                self.state = State::Error(error::Message::new(message));
+                return;
+            }
         };
+        let viewer = ReplayViewer::new(replay);
+        let world = viewer.world().clone();
+        self.camera.set_world(&world);
+        self.camera.center_on(Vec2::new(world.x, world.y) / 2.0);
+        self.fog = Some(Fog::new(&world));
+        self.world_state = Some(world);
+        self.game_state = viewer.game().clone();
+        self.client_id = None;
+        self.spectating = true;
+        self.viewpoint = None;
+        self.replay = Some(viewer);
+        self.state = State::Running;
+    }
+
+    fn save_config(&self) {
+        if let Err(e) = self.config.save() {
+            error!("Could not save configuration: {}", e);
+        }
+    }
+
+    /// Return the player whose view is shown, i.e. the own player or the one a spectator follows.
+    fn viewer(&self) -> Option<ClientId> {
+        if self.spectating {
+            self.viewpoint
+        } else {
+            self.client_id
+        }
+    }
+
+    /// Follow the view of the next player, see `viewpoint_after`.
+    fn next_viewpoint(&mut self) {
+        let players: Vec<ClientId> = self.game_state.players.iter().map(|p| p.id).collect();
+        self.set_viewpoint(viewpoint_after(&players, self.viewpoint));
+    }
+
+    fn set_viewpoint(&mut self, viewpoint: Option<ClientId>) {
+        self.viewpoint = viewpoint;
+        // What was explored belongs to the previous player
+        self.fog = self.world_state.as_ref().map(Fog::new);
+        info!("Viewpoint: {:?}", self.viewpoint);
+    }
+
+    /// Return whether this client may write chat messages. Spectators and replays can't.
+    fn can_chat(&self) -> bool {
+        self.client_id.is_some() && !self.spectating && self.replay.is_none()
+    }
+
+    /// Handle a click on the given position, see `Selection::click`.
+    pub fn select(&mut self, position: Vec2) {
+        let game_state = &self.game_state;
+        let player = self.client_id.and_then(|id| game_state.player(id));
+        let visible_area = self.camera.visible_area();
+        self.selection
+            .click(player, position, visible_area, self.shift, Instant::now());
+    }
+
+    /// Select all own units in the rectangle spanned by the two corners.
+    ///
+    /// The units are added to the current selection while shift is held.
+    pub fn select_rect(&mut self, a: Vec2, b: Vec2) {
+        let game_state = &self.game_state;
+        let player = self.client_id.and_then(|id| game_state.player(id));
+        self.selection.select_rect(player, a, b, self.shift);
+    }
+
+    /// Return the top left and bottom right corner of the visible part of the world.
+    fn visible_area(&self) -> (Vec2, Vec2) {
+        self.camera.visible_area()
+    }
 
-        self.selected_units.truncate(0);
-        if let Some(player) = player {
-            for unit in player.units.iter() {
-                if unit.is_hit(UNIT_SIZE, position) {
-                    self.selected_units.push(unit.id);
+    /// Return the lines along the queued orders of all selected units, together with their colors.
+    fn order_paths(&self) -> Vec<([f32; 4], [f64; 4])> {
+        let mut paths = vec![];
+        for id in self.selection.units.iter() {
+            let unit = match self.game_state.find_unit(*id) {
+                Some((_, unit)) => unit,
+                None => continue,
+            };
+            let mut from = unit.position;
+            for order in unit.orders.iter() {
+                let (color, points) = match order {
+                    Order::Move(target) => (colors::MOVE_ORDER, vec![*target]),
+                    Order::FormationMove { target, .. } => (colors::MOVE_ORDER, vec![*target]),
+                    Order::AttackMove(target) => (colors::ATTACK_ORDER, vec![*target]),
+                    Order::Attack(target_id) => match self.game_state.find_unit(*target_id) {
+                        Some((_, target)) => (colors::ATTACK_ORDER, vec![target.position]),
+                        None => continue,
+                    },
+                    Order::HoldPosition => continue,
+                    Order::Patrol(waypoints, next) => {
+                        // Walk the loop once, starting at the waypoint the unit is heading to
+                        let mut points: Vec<Vec2> = waypoints[*next..]
+                            .iter()
+                            .chain(waypoints[..*next].iter())
+                            .cloned()
+                            .collect();
+                        points.push(waypoints[*next]);
+                        (colors::ATTACK_ORDER, points)
+                    }
+                };
+                for to in points {
+                    paths.push((color, [from.x, from.y, to.x, to.y]));
+                    from = to;
                 }
             }
         }
+        paths
     }
 
-    fn render_game(&mut self, args: &RenderArgs, _: &mut GlyphCache<'_>) {
+    fn render_game(&mut self, args: &RenderArgs, cache: &mut GlyphCache<'_>) {
         use graphics::types::{Line, Polygon};
         use graphics::Transformed;
-        use graphics::{clear, ellipse, line, polygon};
+        use graphics::{clear, ellipse, line, polygon, Rectangle};
 
         const FRONT_THICKNESS: f64 = 5.0;
 
         let game_state = &self.game_state;
         let world = self.world_state.as_ref().unwrap();
         let (wx, wy) = (world.x, world.y);
-        let zoom = self.zoom;
-        let scroll = self.scroll;
-        let selected_units = self.selected_units.clone();
+        let camera = self.camera.clone();
+        let selected_units = self.selection.units.clone();
         let debug = self.debug;
+        let health_bars = self.config.settings.health_bars;
+        let order_paths = self.order_paths();
+        let hovered = selection::unit_at(&self.game_state, self.cursor_position());
+        let cursor = self.cursor;
+        let minimap = self.minimap();
+        let visible_area = self.visible_area();
+        let window_size = self.camera.window_size();
+        let client_id = self.client_id;
+        let chat = &self.chat;
+        // Spectators without a viewpoint see everything
+        let fog = self.viewer().and(self.fog.as_ref());
+        let mut info: Vec<String> = self.replay.iter().map(|replay| replay.status()).collect();
+        if self.spectating {
+            info.push(match self.viewpoint {
+                Some(id) => format!("Spectating player {} (Tab to switch)", id),
+                None => "Spectating all players (Tab to switch)".to_string(),
+            });
+        }
+        let now = Instant::now();
+        let selection: Vec<&Unit> = self
+            .selection
+            .units
+            .iter()
+            .filter_map(|id| game_state.find_unit(*id))
+            .map(|(_, unit)| unit)
+            .collect();
+        let mut debug_lines = vec![
+            format!("FPS: {:.0}", self.fps),
+            format!("Zoom: {:.2}", camera.zoom()),
+        ];
+        debug_lines.extend(
+            self.network_stats
+                .lock()
+                .unwrap()
+                .debug_lines(Instant::now()),
+        );
+        let selection_box = self
+            .drag_start
+            .filter(|start| (cursor - start).magnitude() > DRAG_THRESHOLD)
+            .map(|start| {
+                [
+                    start.x.min(cursor.x),
+                    start.y.min(cursor.y),
+                    (cursor.x - start.x).abs(),
+                    (cursor.y - start.y).abs(),
+                ]
+            });
 
         self.gl.draw(args.viewport(), |c, gl| {
-            let transform = c.transform.scale(zoom, zoom).trans(scroll.x, scroll.y);
+            let transform = camera.transform(c.transform);
 
             // Clear the screen.
             clear(BLACK, gl);
@@ -209,9 +518,12 @@ impl App {
                 line(ORANGE, 1.0, *l, transform, gl);
             }
 
-            for i in 0..game_state.players.len() {
-                let player = &game_state.players[i];
-                let color = &colors::PLAYERS[i % colors::PLAYERS.len()];
+            for (color, l) in order_paths.iter() {
+                line(*color, 1.0, *l, transform, gl);
+            }
+
+            for player in game_state.players.iter() {
+                let color = &colors::PLAYERS[player.color % colors::PLAYERS.len()];
                 for s in player.units.iter() {
                     // Create a triangle polygon. The initial orientation is facing east.
                     let triangle: Polygon<'_> = &s.get_shape(UNIT_SIZE);
@@ -248,6 +560,15 @@ impl App {
                         polygon(color.secondary, triangle, transform_triangle, gl);
                         polygon(color.primary, front, transform_front, gl);
                     }
+                    if hovered == Some(s.id) {
+                        for i in 0..3 {
+                            let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+                            line(HOVER, 1.0, [a[0], a[1], b[0], b[1]], transform, gl);
+                        }
+                    }
+                    if health_bars {
+                        hud::render_health_bar(s, transform, gl);
+                    }
                     if debug {
                         ellipse(
                             TRANSPARENT_WHITE,
@@ -260,14 +581,60 @@ impl App {
             }
             for b in game_state.bullets.iter() {
                 let transform = transform.trans(b.position.x, b.position.y);
-                ellipse(WHITE, [0.0, 0.0, 1.0, 1.0], transform, gl);
+                let r = b.weapon.weapon().radius;
+                ellipse(WHITE, [-r, -r, 2.0 * r, 2.0 * r], transform, gl);
+            }
+
+            if let Some(fog) = fog {
+                fog.render(transform, gl);
             }
+
+            // The selection box and the HUD are drawn in screen coordinates
+            if let Some(rect) = selection_box {
+                Rectangle::new_border(SELECTION_BOX, 1.0).draw(
+                    rect,
+                    &c.draw_state,
+                    c.transform,
+                    gl,
+                );
+            }
+            let mut panel_left = 0.0;
+            if let Some(minimap) = minimap {
+                minimap.render(game_state, fog, visible_area, c.transform, gl);
+                panel_left = minimap.right();
+            }
+            hud::render_selection(&selection, panel_left + 10.0, window_size, &c, cache, gl);
+            hud::render_players(&game_state.players, client_id, window_size, &c, cache, gl);
+            if debug {
+                hud::render_debug(&debug_lines, &c, cache, gl);
+            }
+            hud::render_status(game_state, &info, window_size, &c, cache, gl);
+            let chat_position = Vec2::new(10.0, window_size.y - CHAT_BOTTOM_OFFSET);
+            chat.render(now, client_id, chat_position, &c, cache, gl);
         });
     }
 
     pub fn render(&mut self, args: &RenderArgs, cache: &mut GlyphCache<'_>) {
+        let now = Instant::now();
+        if let Some(last_frame) = self.last_frame {
+            let fps = 1.0 / now.duration_since(last_frame).as_secs_f64().max(1e-6);
+            self.fps = 0.9 * self.fps + 0.1 * fps;
+        }
+        self.last_frame = Some(now);
+        self.camera
+            .set_window_size(Vec2::new(args.window_size[0], args.window_size[1]));
+        if let State::Menu = self.state {
+            self.menu.search_once();
+        }
         match self.state {
-            State::Menu => self.menu.render(args, &mut self.gl, cache),
+            State::Menu => self.menu.render(args, &self.config, &mut self.gl, cache),
+            State::Lobby => self.lobby.render(
+                args,
+                self.client_id,
+                self.chat.messages(),
+                &mut self.gl,
+                cache,
+            ),
             State::Running => self.render_game(args, cache),
             State::Error(ref msg) => msg.render(args, &mut self.gl, cache),
         }
@@ -279,57 +646,168 @@ impl App {
             let mut game_state_lock = self.game_state_server.lock().unwrap();
             (*game_state_lock).take()
         };
-        if let Some(game_state) = game_state_option {
+        let now = Instant::now();
+        for message in self.chat_server.lock().unwrap().drain(..) {
+            self.chat.receive(message, now);
+        }
+        if let Some(lobby_state) = self.lobby_server.lock().unwrap().take() {
+            self.lobby.state = lobby_state;
+        }
+        if let Some(ref mut replay) = self.replay {
+            replay.update(args.dt);
+            self.game_state = replay.game().clone();
+        } else if let Some(game_state) = game_state_option {
+            // The server sends game states once the match started
+            if let State::Lobby = self.state {
+                self.state = State::Running;
+            }
             self.game_state = game_state;
+            self.prune_dead_units();
         } else if let Some(ref world) = self.world_state {
-            self.game_state.update(world, args.dt * 1000.0);
+            // Extrapolate the state the same way the server advances it
+            if self.game_state.is_active() {
+                let dt = args.dt * 1000.0 * self.game_state.speed;
+                self.game_state.update(world, dt);
+            }
+        }
+        if let State::Running = self.state {
+            // Show everything once the followed player left
+            if let Some(id) = self.viewpoint {
+                if self.game_state.player(id).is_none() {
+                    self.set_viewpoint(None);
+                }
+            }
+            if let (Some(viewer), Some(fog)) = (self.viewer(), self.fog.as_mut()) {
+                fog.update(&self.game_state, viewer);
+            }
+            if self.config.settings.edge_pan {
+                self.camera.edge_pan(self.cursor, args.dt);
+            }
         }
     }
 
     pub fn on_button_press(&mut self, button: &Button) -> bool {
+        // Modifiers are tracked in every state, so that they are known after e.g. typing a chat
+        // message
+        match button {
+            Button::Keyboard(Key::LShift) | Button::Keyboard(Key::RShift) => self.shift = true,
+            Button::Keyboard(Key::LCtrl) | Button::Keyboard(Key::RCtrl) => self.ctrl = true,
+            _ => {}
+        }
+        if let (Some(replay), Button::Keyboard(key)) = (self.replay.as_mut(), button) {
+            if replay.on_key(*key) {
+                return false;
+            }
+        }
         match self.state {
-            State::Menu => match button {
-                Button::Keyboard(Key::Up) => {
-                    self.menu.previous();
-                }
-                Button::Keyboard(Key::Down) => {
-                    self.menu.next();
-                }
-                Button::Keyboard(Key::Return) => match self.menu.get_selected_entry() {
-                    menu::Entries::Start => match self.start() {
-                        Ok(_) => {
-                            self.state = State::Running;
+            State::Menu => {
+                if let Button::Keyboard(key) = button {
+                    let settings = self.config.settings.clone();
+                    let action = self.menu.on_key(*key, &mut self.config);
+                    if self.config.settings != settings {
+                        self.save_config();
+                    }
+                    match action {
+                        Some(menu::Action::Connect(server, reconnect)) => {
+                            let hello = match reconnect {
+                                Some(id) => Message::ClientReconnect(id),
+                                None => Message::ClientHello,
+                            };
+                            self.join(server, hello);
                         }
-                        Err(err) => {
-                            self.state = State::Error(error::Message::new(err.to_string()));
+                        Some(menu::Action::Spectate(server)) => {
+                            self.join(server, Message::SpectatorHello);
                         }
-                    },
-                    menu::Entries::Exit => {
-                        return true;
+                        Some(menu::Action::Invalid(message)) => {
+                            self.state = State::Error(error::Message::new(message));
+                        }
+                        Some(menu::Action::Exit) => {
+                            return true;
+                        }
+                        None => {}
                     }
-                },
-                _ => {}
-            },
+                }
+            }
+            State::Lobby => {
+                if let (Button::Keyboard(key), Some(client_id)) = (button, self.client_id) {
+                    if let Some(message) = self.lobby.on_key(*key, client_id) {
+                        self.send(message);
+                    }
+                }
+            }
+            State::Running if self.chat.is_typing() => {
+                if let (Button::Keyboard(key), Some(client_id)) = (button, self.client_id) {
+                    let players: Vec<ClientId> =
+                        self.game_state.players.iter().map(|p| p.id).collect();
+                    if let Some(message) = self.chat.on_key(*key, client_id, &players) {
+                        self.send(message);
+                    }
+                } else if let Button::Mouse(button) = button {
+                    self.on_mouse_click(button);
+                }
+            }
             State::Running => match button {
+                Button::Keyboard(Key::Tab) if self.spectating => {
+                    self.next_viewpoint();
+                }
+                Button::Keyboard(Key::Return) if self.can_chat() => {
+                    self.chat.open();
+                }
+                Button::Keyboard(Key::Space) => {
+                    self.send(Message::Pause(!self.game_state.paused));
+                }
+                Button::Keyboard(Key::Equals) | Button::Keyboard(Key::NumPadPlus) => {
+                    self.send(Message::SetSpeed(self.game_state.speed * 2.0));
+                }
+                Button::Keyboard(Key::Minus) | Button::Keyboard(Key::NumPadMinus) => {
+                    self.send(Message::SetSpeed(self.game_state.speed / 2.0));
+                }
                 Button::Keyboard(Key::Up) => {
-                    self.scroll.y += 10.0;
+                    self.camera.pan(Vec2::new(0.0, -KEY_PAN_DISTANCE));
                 }
                 Button::Keyboard(Key::Down) => {
-                    self.scroll.y -= 10.0;
+                    self.camera.pan(Vec2::new(0.0, KEY_PAN_DISTANCE));
                 }
                 Button::Keyboard(Key::Left) => {
-                    self.scroll.x += 10.0;
+                    self.camera.pan(Vec2::new(-KEY_PAN_DISTANCE, 0.0));
                 }
                 Button::Keyboard(Key::Right) => {
-                    self.scroll.x -= 10.0;
+                    self.camera.pan(Vec2::new(KEY_PAN_DISTANCE, 0.0));
                 }
                 Button::Keyboard(Key::F) => {
                     self.shoot();
                 }
+                Button::Keyboard(Key::A) => {
+                    self.attack_move(self.cursor_position());
+                }
+                Button::Keyboard(Key::T) => {
+                    self.toggle_stance();
+                }
+                Button::Keyboard(Key::G) => {
+                    self.formation = self.formation.next();
+                    info!("formation: {:?}", self.formation);
+                }
+                Button::Keyboard(Key::S) => {
+                    self.stop();
+                }
+                Button::Keyboard(Key::H) => {
+                    self.hold_position();
+                }
+                Button::Keyboard(Key::P) => {
+                    self.patrol(self.cursor_position());
+                }
                 Button::Keyboard(Key::D) => {
                     self.debug = !self.debug;
                 }
-                Button::Keyboard(_) => {}
+                Button::Keyboard(key) => {
+                    if let Some(index) = ControlGroups::index(*key) {
+                        if self.ctrl {
+                            self.store_control_group(index);
+                        } else {
+                            self.recall_control_group(index);
+                        }
+                    }
+                }
                 Button::Mouse(button) => {
                     self.on_mouse_click(button);
                 }
@@ -345,39 +823,274 @@ impl App {
         false
     }
 
+    pub fn on_button_release(&mut self, button: &Button) {
+        match button {
+            Button::Keyboard(Key::LShift) | Button::Keyboard(Key::RShift) => {
+                self.shift = false;
+            }
+            Button::Keyboard(Key::LCtrl) | Button::Keyboard(Key::RCtrl) => {
+                self.ctrl = false;
+            }
+            Button::Mouse(MouseButton::Left) => {
+                self.minimap_drag = false;
+                if let Some(start) = self.drag_start.take() {
+                    if (self.cursor - start).magnitude() > DRAG_THRESHOLD {
+                        self.select_rect(self.to_world(start), self.cursor_position());
+                    } else {
+                        self.select(self.cursor_position());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Remove units that don't exist anymore from the selection and all control groups.
+    fn prune_dead_units(&mut self) {
+        let game_state = &self.game_state;
+        self.selection
+            .units
+            .retain(|id| game_state.find_unit(*id).is_some());
+        self.control_groups.prune(game_state);
+    }
+
+    pub fn store_control_group(&mut self, index: usize) {
+        self.control_groups.store(index, &self.selection.units);
+    }
+
+    /// Select the units of the control group and center the camera on them when the group is
+    /// recalled twice in a row.
+    pub fn recall_control_group(&mut self, index: usize) {
+        let (units, double_tap) = self.control_groups.recall(index, Instant::now());
+        self.selection.units = units;
+        if double_tap {
+            if let Some(center) = control_groups::center(&self.game_state, &self.selection.units) {
+                self.camera.center_on(center);
+            }
+        }
+    }
+
+    fn minimap(&self) -> Option<Minimap> {
+        self.world_state
+            .as_ref()
+            .map(|world| Minimap::new(world, self.camera.window_size()))
+    }
+
+    /// Return the world position under the cursor if it is on the minimap.
+    fn minimap_position(&self) -> Option<Vec2> {
+        self.minimap()?.to_world(self.cursor)
+    }
+
+    /// Convert a position on the screen to world coordinates.
+    fn to_world(&self, position: Vec2) -> Vec2 {
+        self.camera.to_world(position)
+    }
+
+    /// Return the position of the mouse cursor in world coordinates.
+    fn cursor_position(&self) -> Vec2 {
+        self.to_world(self.cursor)
+    }
+
+    /// Return the ID of the enemy unit at the given position, if any.
+    fn enemy_at(&self, position: Vec2) -> Option<UnitId> {
+        self.game_state
+            .players
+            .iter()
+            .filter(|player| Some(player.id) != self.client_id)
+            .flat_map(|player| player.units.iter())
+            .find(|unit| unit.is_hit(UNIT_SIZE, position))
+            .map(|unit| unit.id)
+    }
+
     pub fn on_mouse_click(&mut self, button: &MouseButton) {
-        let cursor = self.cursor / self.zoom - self.scroll;
+        // Clicks on the minimap jump there instead of reaching the world below it
+        if let Some(position) = self.minimap_position() {
+            match *button {
+                MouseButton::Left => {
+                    self.minimap_drag = true;
+                    self.camera.center_on(position);
+                }
+                MouseButton::Right if self.shift => self.queue_waypoint(position),
+                MouseButton::Right => self.move_selected(position),
+                _ => {}
+            }
+            return;
+        }
+
+        let cursor = self.cursor_position();
         match *button {
-            MouseButton::Left => self.select(cursor),
-            MouseButton::Right => self.move_selected(cursor),
+            // Selection happens when the button is released
+            MouseButton::Left => self.drag_start = Some(self.cursor),
+            MouseButton::Right => match self.enemy_at(cursor) {
+                Some(target) => self.attack(target),
+                None if self.shift => self.queue_waypoint(cursor),
+                None => self.move_selected(cursor),
+            },
             _ => info!("Pressed mouse button '{:?}'", button),
         }
     }
 
+    pub fn on_text(&mut self, text: &str) {
+        match self.state {
+            State::Menu => self.menu.on_text(text, &self.config),
+            State::Lobby => self.lobby.on_text(text),
+            State::Running => self.chat.on_text(text),
+            _ => {}
+        }
+    }
+
     pub fn on_mouse_move(&mut self, cursor: Vec2) {
         self.cursor = cursor;
+        if self.minimap_drag {
+            if let Some(position) = self.minimap_position() {
+                self.camera.center_on(position);
+            }
+        }
     }
 
     pub fn on_mouse_scroll(&mut self, scroll: Vec2) {
-        if scroll.y > 0.0 {
-            self.zoom *= 1.5 * scroll.y;
-        } else {
-            self.zoom /= 1.5 * -scroll.y;
+        if let State::Running = self.state {
+            self.camera.zoom_at(self.cursor, scroll.y);
+        }
+    }
+
+    fn send(&self, message: Message) {
+        self.outgoing.lock().unwrap().push_back(message);
+    }
+
+    /// Send a command for all selected units to the server.
+    fn command_selected<F: FnOnce(Vec<UnitId>) -> Command>(&self, command: F) {
+        if self.selection.units.is_empty() {
+            return;
         }
-        info!("zoom: {}", self.zoom);
+        self.send(Message::Command(command(self.selection.units.clone())));
     }
 
     pub fn move_selected(&mut self, position: Vec2) {
-        for u in self.selected_units.iter() {
-            let mut commands = self.commands.lock().unwrap();
-            commands.push_back(Command::Move(*u, position));
+        let formation = self.formation;
+        self.command_selected(|units| Command::Move(units, position, formation));
+    }
+
+    pub fn queue_waypoint(&mut self, position: Vec2) {
+        let formation = self.formation;
+        self.command_selected(|units| Command::Waypoint(units, position, formation));
+    }
+
+    pub fn attack(&mut self, target: UnitId) {
+        self.command_selected(|units| Command::Attack(units, target));
+    }
+
+    pub fn attack_move(&mut self, position: Vec2) {
+        let formation = self.formation;
+        self.command_selected(|units| Command::AttackMove(units, position, formation));
+    }
+
+    pub fn stop(&mut self) {
+        self.command_selected(Command::Stop);
+    }
+
+    pub fn hold_position(&mut self) {
+        self.command_selected(Command::HoldPosition);
+    }
+
+    pub fn patrol(&mut self, position: Vec2) {
+        self.command_selected(|units| Command::Patrol(units, vec![position]));
+    }
+
+    /// Switch all selected units to the stance following the one of the first selected unit.
+    pub fn toggle_stance(&mut self) {
+        let current = self
+            .selection
+            .units
+            .first()
+            .and_then(|id| self.game_state.find_unit(*id).map(|(_, unit)| unit.stance));
+        if let Some(current) = current {
+            let stance = current.next();
+            info!("stance: {:?}", stance);
+            self.command_selected(|units| Command::SetStance(units, stance));
         }
     }
 
     pub fn shoot(&mut self) {
-        for u in self.selected_units.iter() {
-            let mut commands = self.commands.lock().unwrap();
-            commands.push_back(Command::Shoot(*u));
-        }
+        self.command_selected(Command::Shoot);
+    }
+}
+
+/// Return the player a spectator follows after `current` among `players`.
+///
+/// After the last player the whole match is shown, which is followed by the first player again.
+/// If the followed player left, the first player is next as well.
+fn viewpoint_after(players: &[ClientId], current: Option<ClientId>) -> Option<ClientId> {
+    match current.and_then(|id| players.iter().position(|player| *player == id)) {
+        Some(index) => players.get(index + 1).cloned(),
+        None => players.first().cloned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::server::Server;
+
+    #[test]
+    fn test_viewpoint_after() {
+        //! Spectators cycle through the players and the full view.
+        let players = [ClientId(1), ClientId(4), ClientId(2)];
+        assert_eq!(viewpoint_after(&players, None), Some(ClientId(1)));
+        assert_eq!(
+            viewpoint_after(&players, Some(ClientId(1))),
+            Some(ClientId(4))
+        );
+        assert_eq!(
+            viewpoint_after(&players, Some(ClientId(4))),
+            Some(ClientId(2))
+        );
+        assert_eq!(viewpoint_after(&players, Some(ClientId(2))), None);
+        // The followed player left
+        assert_eq!(
+            viewpoint_after(&players, Some(ClientId(3))),
+            Some(ClientId(1))
+        );
+        assert_eq!(viewpoint_after(&[], None), None);
+        assert_eq!(viewpoint_after(&[], Some(ClientId(3))), None);
+    }
+
+    #[test]
+    fn test_connect_as_spectator() {
+        //! Spectators get an ID of their own, but don't join the lobby.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut server = Server::new(("127.0.0.1", port), (800.0, 600.0)).unwrap();
+        server.set_discovery_port(None);
+        thread::spawn(move || server.serve());
+
+        let connect = |hello: fn() -> Message| {
+            let mut client = NetworkClient::new(
+                ("127.0.0.1", port),
+                Arc::new(Mutex::new(None)),
+                Arc::new(Mutex::new(VecDeque::new())),
+                Arc::new(Mutex::new(None)),
+                Arc::new(Mutex::new(vec![])),
+                Arc::new(Mutex::new(NetworkStats::new())),
+            )
+            .unwrap();
+            // The server may not listen yet
+            for _ in 0..50 {
+                match client.connect(hello()) {
+                    Ok(connected) => return connected,
+                    Err(_) => thread::sleep(time::Duration::from_millis(20)),
+                }
+            }
+            panic!("Could not connect to the server");
+        };
+        let (spectator, world) = connect(|| Message::SpectatorHello);
+        assert_eq!(world, WorldState::new(800.0, 600.0));
+        let (player, _) = connect(|| Message::ClientHello);
+        assert_ne!(spectator, player);
     }
 }
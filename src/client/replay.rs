@@ -0,0 +1,127 @@
+//! Playback of a recorded match with pause, seek and speed controls.
+
+use piston::input::Key;
+
+use crate::replay::{Playback, Replay};
+use crate::server::UPDATE_INTERVAL_MS;
+use crate::state::{GameState, WorldState};
+
+/// Slowest and fastest playback speed
+const MIN_SPEED: f64 = 0.25;
+const MAX_SPEED: f64 = 8.0;
+
+/// Time skipped with a single key press, in milli seconds
+const SEEK_STEP_MS: f64 = 10_000.0;
+
+/// Most simulation steps run in one update, so a slow machine doesn't fall further behind
+const MAX_STEPS_PER_UPDATE: usize = 200;
+
+#[derive(Debug, Clone)]
+pub struct ReplayViewer {
+    playback: Playback,
+    paused: bool,
+    speed: f64,
+    /// Playback time in milli seconds not yet covered by simulation steps
+    pending: f64,
+}
+
+impl ReplayViewer {
+    pub fn new(replay: Replay) -> ReplayViewer {
+        ReplayViewer {
+            playback: Playback::new(replay),
+            paused: false,
+            speed: 1.0,
+            pending: 0.0,
+        }
+    }
+
+    pub fn world(&self) -> &WorldState {
+        self.playback.world()
+    }
+
+    pub fn game(&self) -> &GameState {
+        self.playback.game()
+    }
+
+    /// Advance the playback by `dt` seconds of real time.
+    pub fn update(&mut self, dt: f64) {
+        if self.paused {
+            return;
+        }
+        self.pending += dt * 1000.0 * self.speed;
+        let mut steps = 0;
+        while self.pending >= UPDATE_INTERVAL_MS && steps < MAX_STEPS_PER_UPDATE {
+            self.playback.step();
+            self.pending -= UPDATE_INTERVAL_MS;
+            steps += 1;
+        }
+        self.pending = self.pending.min(UPDATE_INTERVAL_MS);
+    }
+
+    /// Handle a key press and return whether it controlled the playback.
+    ///
+    /// Space pauses, +/- change the speed, PageUp/PageDown seek and Home restarts.
+    pub fn on_key(&mut self, key: Key) -> bool {
+        let seek_ticks = (SEEK_STEP_MS / UPDATE_INTERVAL_MS) as u64;
+        let tick = self.game().tick;
+        match key {
+            Key::Space => self.paused = !self.paused,
+            Key::Equals | Key::NumPadPlus => self.speed = (self.speed * 2.0).min(MAX_SPEED),
+            Key::Minus | Key::NumPadMinus => self.speed = (self.speed / 2.0).max(MIN_SPEED),
+            Key::PageUp => self.playback.seek(tick + seek_ticks),
+            Key::PageDown => self.playback.seek(tick.saturating_sub(seek_ticks)),
+            Key::Home => self.playback.seek(0),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Return a description of the playback position and state.
+    pub fn status(&self) -> String {
+        let seconds = |tick: u64| tick as f64 * UPDATE_INTERVAL_MS / 1000.0;
+        format!(
+            "Replay {:.1} s (last event at {:.1} s), {}x{}",
+            seconds(self.game().tick),
+            seconds(self.playback.replay().last_tick()),
+            self.speed,
+            if self.paused { ", paused" } else { "" }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lobby::MatchSettings;
+    use crate::replay::ReplayHeader;
+
+    #[test]
+    fn test_controls() {
+        let header = ReplayHeader::new(
+            WorldState::new(800.0, 600.0),
+            MatchSettings::default(),
+            0,
+            GameState::new(),
+        );
+        let mut viewer = ReplayViewer::new(Replay {
+            header,
+            events: vec![],
+        });
+        viewer.update(0.1);
+        assert_eq!(viewer.game().tick, 20);
+
+        assert!(viewer.on_key(Key::Equals));
+        viewer.update(0.1);
+        assert_eq!(viewer.game().tick, 60);
+
+        assert!(viewer.on_key(Key::Space));
+        viewer.update(0.1);
+        assert_eq!(viewer.game().tick, 60);
+
+        assert!(viewer.on_key(Key::PageUp));
+        assert_eq!(viewer.game().tick, 2060);
+        assert!(viewer.on_key(Key::Home));
+        assert_eq!(viewer.game().tick, 0);
+        assert!(!viewer.on_key(Key::Tab));
+    }
+}
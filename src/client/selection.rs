@@ -0,0 +1,237 @@
+//! The units the player has selected and the rules for changing the selection with the mouse.
+
+use std::time::{Duration, Instant};
+
+use crate::common::Vec2;
+use crate::shapes::Shape;
+use crate::state::{GameState, Player, Unit, UnitId, UNIT_SIZE};
+
+/// Maximum time between two clicks of a double click
+pub const DOUBLE_CLICK_TIME: Duration = Duration::from_millis(400);
+
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    /// Selected units in the order they were selected
+    pub units: Vec<UnitId>,
+    /// Time and unit of the last click on a unit, to detect double clicks
+    last_click: Option<(Instant, UnitId)>,
+}
+
+impl Selection {
+    pub fn new() -> Selection {
+        Selection::default()
+    }
+
+    /// Handle a click on the given world position at time `now`.
+    ///
+    /// A click selects the own unit below the cursor, or adds and removes it from the selection
+    /// while shift is held. A double click selects all own units of the same kind in
+    /// `visible_area`, given by its top left and bottom right corner.
+    pub fn click(
+        &mut self,
+        player: Option<&Player>,
+        position: Vec2,
+        visible_area: (Vec2, Vec2),
+        shift: bool,
+        now: Instant,
+    ) {
+        let unit = player
+            .and_then(|player| {
+                player
+                    .units
+                    .iter()
+                    .find(|unit| unit.is_hit(UNIT_SIZE, position))
+            })
+            .map(|unit| (unit.id, unit.weapon));
+
+        let double_click = match (self.last_click, unit) {
+            (Some((time, last_id)), Some((id, _))) => {
+                last_id == id && now.duration_since(time) < DOUBLE_CLICK_TIME
+            }
+            _ => false,
+        };
+        self.last_click = unit.map(|(id, _)| (now, id));
+
+        match unit {
+            Some((_, weapon)) if double_click => {
+                let (top_left, bottom_right) = visible_area;
+                let units: Vec<UnitId> = player
+                    .map(|player| units_in_rect(player, top_left, bottom_right))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|unit| unit.weapon == weapon)
+                    .map(|unit| unit.id)
+                    .collect();
+                if !shift {
+                    self.units.clear();
+                }
+                self.add(units);
+            }
+            Some((id, _)) if shift => {
+                match self.units.iter().position(|selected| *selected == id) {
+                    Some(index) => {
+                        self.units.remove(index);
+                    }
+                    None => self.units.push(id),
+                }
+            }
+            _ => {
+                if !shift {
+                    self.units.clear();
+                }
+                self.add(unit.map(|(id, _)| id));
+            }
+        }
+    }
+
+    /// Select all own units in the rectangle spanned by the two corners.
+    ///
+    /// The units are added to the current selection while shift is held.
+    pub fn select_rect(&mut self, player: Option<&Player>, a: Vec2, b: Vec2, shift: bool) {
+        let units: Vec<UnitId> = player
+            .map(|player| units_in_rect(player, a, b))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|unit| unit.id)
+            .collect();
+        if !shift {
+            self.units.clear();
+        }
+        self.add(units);
+    }
+
+    fn add<T: IntoIterator<Item = UnitId>>(&mut self, units: T) {
+        for id in units {
+            if !self.units.contains(&id) {
+                self.units.push(id);
+            }
+        }
+    }
+}
+
+/// Return all units of the player with their center in the rectangle spanned by the two corners.
+pub fn units_in_rect(player: &Player, a: Vec2, b: Vec2) -> Vec<&Unit> {
+    let (min_x, max_x) = (a.x.min(b.x), a.x.max(b.x));
+    let (min_y, max_y) = (a.y.min(b.y), a.y.max(b.y));
+    player
+        .units
+        .iter()
+        .filter(|unit| {
+            let p = unit.position;
+            p.x >= min_x && p.x <= max_x && p.y >= min_y && p.y <= max_y
+        })
+        .collect()
+}
+
+/// Return the ID of any unit at the given position, the one highlighted under the cursor.
+pub fn unit_at(game: &GameState, position: Vec2) -> Option<UnitId> {
+    game.players
+        .iter()
+        .flat_map(|player| player.units.iter())
+        .find(|unit| unit.is_hit(UNIT_SIZE, position))
+        .map(|unit| unit.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::WeaponKind;
+
+    /// A player with rifles at (100, 100) and (200, 100) and a cannon at (300, 100).
+    fn player() -> Player {
+        let mut player = Player::new(1);
+        for (id, x) in [(1, 100.0), (2, 200.0), (3, 300.0)] {
+            player.units.push(Unit::new(id, Vec2::new(x, 100.0)));
+        }
+        player.units[2].weapon = WeaponKind::Cannon;
+        player
+    }
+
+    fn ids(ids: &[u32]) -> Vec<UnitId> {
+        ids.iter().map(|id| UnitId(*id)).collect()
+    }
+
+    #[test]
+    fn test_select_rect() {
+        let player = player();
+        let mut selection = Selection::new();
+        let (a, b) = (Vec2::new(250.0, 0.0), Vec2::new(150.0, 200.0));
+        selection.select_rect(Some(&player), a, b, false);
+        assert_eq!(selection.units, ids(&[2]));
+
+        // Corners in any order, shift adds to the selection
+        let (a, b) = (Vec2::new(350.0, 150.0), Vec2::new(250.0, 50.0));
+        selection.select_rect(Some(&player), a, b, true);
+        assert_eq!(selection.units, ids(&[2, 3]));
+        selection.select_rect(Some(&player), a, b, false);
+        assert_eq!(selection.units, ids(&[3]));
+
+        selection.select_rect(None, a, b, false);
+        assert!(selection.units.is_empty());
+    }
+
+    #[test]
+    fn test_click() {
+        //! A click selects a single unit, shift toggles units, a click on nothing deselects.
+        let player = player();
+        let area = (Vec2::new(0.0, 0.0), Vec2::new(1000.0, 1000.0));
+        let start = Instant::now();
+        let mut selection = Selection::new();
+        let mut click = |x, shift, n: u32| {
+            let position = Vec2::new(x, 100.0);
+            let time = start + DOUBLE_CLICK_TIME * n;
+            selection.click(Some(&player), position, area, shift, time);
+            selection.units.clone()
+        };
+
+        assert_eq!(click(100.0, false, 0), ids(&[1]));
+        assert_eq!(click(300.0, true, 1), ids(&[1, 3]));
+        assert_eq!(click(100.0, true, 2), ids(&[3]));
+        assert_eq!(click(500.0, true, 3), ids(&[3]));
+        assert!(click(500.0, false, 4).is_empty());
+    }
+
+    #[test]
+    fn test_double_click() {
+        //! A double click selects all units with the same weapon in the visible area.
+        let player = player();
+        let everything = (Vec2::new(0.0, 0.0), Vec2::new(1000.0, 1000.0));
+        let corner = (Vec2::new(0.0, 0.0), Vec2::new(150.0, 150.0));
+        let start = Instant::now();
+        let mut selection = Selection::new();
+        let mut click = |x, area, shift, ms: u64| {
+            let position = Vec2::new(x, 100.0);
+            let time = start + Duration::from_millis(ms);
+            selection.click(Some(&player), position, area, shift, time);
+            selection.units.clone()
+        };
+
+        click(100.0, everything, false, 0);
+        assert_eq!(click(100.0, everything, false, 100), ids(&[1, 2]));
+
+        // Only units in the visible area
+        click(100.0, corner, false, 1000);
+        assert_eq!(click(100.0, corner, false, 1100), ids(&[1]));
+
+        // Too slow for a double click
+        click(200.0, everything, false, 2000);
+        assert_eq!(click(200.0, everything, false, 2400), ids(&[2]));
+
+        // Shift adds the units of the kind to the selection
+        click(300.0, everything, true, 3000);
+        assert_eq!(click(300.0, everything, true, 3100), ids(&[2, 3]));
+    }
+
+    #[test]
+    fn test_unit_at() {
+        //! Units of all players can be hovered.
+        let mut game = GameState::new();
+        game.players.push(player());
+        let mut enemy = Player::new(2);
+        enemy.units.push(Unit::new(4, Vec2::new(100.0, 300.0)));
+        game.players.push(enemy);
+        assert_eq!(unit_at(&game, Vec2::new(102.0, 98.0)), Some(UnitId(1)));
+        assert_eq!(unit_at(&game, Vec2::new(100.0, 300.0)), Some(UnitId(4)));
+        assert_eq!(unit_at(&game, Vec2::new(100.0, 200.0)), None);
+    }
+}
@@ -0,0 +1,124 @@
+//! Connection quality estimates shown in the debug overlay.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Time window in which received snapshots are counted
+const SNAPSHOT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Round trip time, jitter and snapshot rate of the connection to the server.
+#[derive(Debug, Default)]
+pub struct NetworkStats {
+    /// Smoothed round trip time in ms
+    rtt: Option<f64>,
+    /// Mean deviation between consecutive round trip times in ms
+    jitter: f64,
+    /// Last measured round trip time in ms
+    last_rtt: Option<f64>,
+    /// Receive times of the snapshots within the last `SNAPSHOT_WINDOW`
+    snapshots: VecDeque<Instant>,
+    /// Tick of the last received snapshot
+    tick: u64,
+}
+
+impl NetworkStats {
+    pub fn new() -> NetworkStats {
+        NetworkStats::default()
+    }
+
+    /// Add a round trip time measurement in ms.
+    ///
+    /// The estimates are smoothed like TCP does for its retransmission timer (RFC 6298) and RTP
+    /// for its interarrival jitter (RFC 3550).
+    pub fn on_pong(&mut self, rtt: f64) {
+        self.rtt = Some(match self.rtt {
+            Some(srtt) => 0.875 * srtt + 0.125 * rtt,
+            None => rtt,
+        });
+        if let Some(last_rtt) = self.last_rtt {
+            self.jitter += ((rtt - last_rtt).abs() - self.jitter) / 16.0;
+        }
+        self.last_rtt = Some(rtt);
+    }
+
+    /// Register a game state snapshot received at `now`.
+    pub fn on_snapshot(&mut self, now: Instant, tick: u64) {
+        self.snapshots.push_back(now);
+        self.tick = tick;
+        self.forget_snapshots(now);
+    }
+
+    fn forget_snapshots(&mut self, now: Instant) {
+        while let Some(time) = self.snapshots.front() {
+            if now.duration_since(*time) > SNAPSHOT_WINDOW {
+                self.snapshots.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub fn rtt(&self) -> Option<f64> {
+        self.rtt
+    }
+
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Return the number of snapshots received during the last second.
+    pub fn snapshots_per_second(&mut self, now: Instant) -> usize {
+        self.forget_snapshots(now);
+        self.snapshots.len()
+    }
+
+    /// Return the lines shown in the debug overlay.
+    pub fn debug_lines(&mut self, now: Instant) -> Vec<String> {
+        let ping = match self.rtt {
+            Some(rtt) => format!("Ping: {:.1} ms (jitter {:.1} ms)", rtt, self.jitter),
+            None => "Ping: -".to_string(),
+        };
+        vec![
+            ping,
+            format!("Snapshots/s: {}", self.snapshots_per_second(now)),
+            format!("Server tick: {}", self.tick),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt() {
+        //! The first measurement is taken as is, later ones are smoothed.
+        let mut stats = NetworkStats::new();
+        assert_eq!(stats.rtt(), None);
+        stats.on_pong(40.0);
+        assert_eq!(stats.rtt(), Some(40.0));
+        assert_eq!(stats.jitter(), 0.0);
+        stats.on_pong(80.0);
+        assert_eq!(stats.rtt(), Some(45.0));
+        assert_eq!(stats.jitter(), 2.5);
+    }
+
+    #[test]
+    fn test_snapshots_per_second() {
+        //! Only snapshots of the last second are counted.
+        let mut stats = NetworkStats::new();
+        let start = Instant::now();
+        for i in 0..5 {
+            stats.on_snapshot(start + Duration::from_millis(i * 300), i);
+        }
+        assert_eq!(stats.tick(), 4);
+        let now = start + Duration::from_millis(1200);
+        assert_eq!(stats.snapshots_per_second(now), 4);
+        let later = start + Duration::from_millis(3000);
+        assert_eq!(stats.snapshots_per_second(later), 0);
+    }
+}
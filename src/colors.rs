@@ -15,13 +15,31 @@ pub const ORANGE: [f32; 4] = [1.0, 0.61, 0.22, 1.0];
 pub const RED: [f32; 4] = [1.0, 0.22, 0.22, 1.0];
 pub const LIGHT_BLUE: [f32; 4] = [0.22, 0.22, 1.0, 1.0];
 pub const BLUE: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
+pub const GREEN: [f32; 4] = [0.0, 0.6, 0.0, 1.0];
+pub const LIGHT_GREEN: [f32; 4] = [0.4, 1.0, 0.4, 1.0];
+pub const PURPLE: [f32; 4] = [0.6, 0.0, 0.8, 1.0];
+pub const PINK: [f32; 4] = [1.0, 0.5, 0.9, 1.0];
+pub const MOVE_ORDER: [f32; 4] = [0.22, 1.0, 0.22, 0.6];
+pub const ATTACK_ORDER: [f32; 4] = [1.0, 0.22, 0.22, 0.6];
+pub const SELECTION_BOX: [f32; 4] = [0.22, 1.0, 0.22, 1.0];
+pub const HOVER: [f32; 4] = [1.0, 1.0, 1.0, 0.8];
+pub const FOG_UNEXPLORED: [f32; 4] = [0.0, 0.0, 0.0, 0.9];
+pub const FOG_EXPLORED: [f32; 4] = [0.0, 0.0, 0.0, 0.5];
+pub const MINIMAP_BACKGROUND: [f32; 4] = [0.1, 0.1, 0.1, 0.8];
+pub const MINIMAP_VIEWPORT: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+pub const HEALTH_BAR: [f32; 4] = [0.22, 1.0, 0.22, 1.0];
+pub const HEALTH_BAR_BACKGROUND: [f32; 4] = [1.0, 0.22, 0.22, 0.6];
+pub const HUD_BACKGROUND: [f32; 4] = [0.1, 0.1, 0.1, 0.8];
+pub const PAUSE_OVERLAY: [f32; 4] = [0.0, 0.0, 0.0, 0.5];
+pub const CHAT: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+pub const CHAT_INPUT: [f32; 4] = [1.0, 1.0, 0.22, 1.0];
 
 pub struct Player {
     pub primary: [f32; 4],
     pub secondary: [f32; 4],
 }
 
-pub const PLAYERS: [Player; 3] = [
+pub const PLAYERS: [Player; 5] = [
     Player {
         primary: ORANGE,
         secondary: YELLOW,
@@ -34,4 +52,12 @@ pub const PLAYERS: [Player; 3] = [
         primary: BLUE,
         secondary: LIGHT_BLUE,
     },
+    Player {
+        primary: GREEN,
+        secondary: LIGHT_GREEN,
+    },
+    Player {
+        primary: PURPLE,
+        secondary: PINK,
+    },
 ];
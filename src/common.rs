@@ -3,3 +3,47 @@ use cgmath::Vector2;
 
 /// Cartesian x/y coordinates using f64.
 pub type Vec2 = Vector2<f64>;
+
+/// A path in the temporary directory for tests, removed together with its contents when dropped.
+#[cfg(test)]
+pub struct TempPath(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempPath {
+    /// Create a path unique to this test process, `name` distinguishes the tests.
+    pub fn new(name: &str) -> TempPath {
+        TempPath(std::env::temp_dir().join(format!(
+            "rpsrtsrs-test-{}-{}",
+            std::process::id(),
+            name
+        )))
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempPath {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<std::path::Path> for TempPath {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        // The test may have failed before creating it
+        let _ = if self.0.is_dir() {
+            std::fs::remove_dir_all(&self.0)
+        } else {
+            std::fs::remove_file(&self.0)
+        };
+    }
+}
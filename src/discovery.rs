@@ -0,0 +1,252 @@
+//! Discovery of servers in the local network.
+//!
+//! Clients broadcast a `Probe` over UDP and every server listening on the discovery port answers
+//! with an `Announce` describing itself.
+
+use std::io::{ErrorKind, Result as IoResult};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use bincode::Options;
+
+use crate::network::MAX_MESSAGE_SIZE;
+use crate::state::MatchState;
+
+/// UDP port servers listen on for probes by default
+pub const DISCOVERY_PORT: u16 = 8079;
+
+/// Identifies discovery messages of this game, other traffic on the port is ignored
+const MAGIC: u32 = 0x7270_7372;
+
+/// Description of a server sent in answer to a probe.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct ServerInfo {
+    pub name: String,
+    /// TCP port the game is served on
+    pub port: u16,
+    pub players: usize,
+    pub state: MatchState,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+enum DiscoveryMessage {
+    Probe(u32),
+    Announce(u32, ServerInfo),
+}
+
+/// A server that answered a probe.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct DiscoveredServer {
+    /// Address of the game server, i.e. the sender's IP with the announced port
+    pub addr: SocketAddr,
+    pub info: ServerInfo,
+}
+
+/// Answer probes received on `socket` with the information returned by `info`.
+pub fn serve<F: Fn() -> ServerInfo>(socket: &UdpSocket, info: F) {
+    let bincode = bincode::DefaultOptions::new().with_limit(MAX_MESSAGE_SIZE);
+    let mut buffer = [0; 512];
+    loop {
+        let (length, sender) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) => {
+                error!("Discovery: {:?}", e);
+                continue;
+            }
+        };
+        match bincode.deserialize(&buffer[..length]) {
+            Ok(DiscoveryMessage::Probe(MAGIC)) => {
+                debug!("Discovery probe from {}", sender);
+                let announce = DiscoveryMessage::Announce(MAGIC, info());
+                if let Err(e) = socket.send_to(&bincode.serialize(&announce).unwrap(), sender) {
+                    error!("Discovery: {:?}", e);
+                }
+            }
+            _ => debug!("Ignoring discovery message from {}", sender),
+        }
+    }
+}
+
+/// Send a probe to `target`, which usually is a broadcast address, and collect the answers that
+/// arrive within `timeout`.
+pub fn discover<T: ToSocketAddrs>(
+    socket: &UdpSocket,
+    target: T,
+    timeout: Duration,
+) -> IoResult<Vec<DiscoveredServer>> {
+    let bincode = bincode::DefaultOptions::new().with_limit(MAX_MESSAGE_SIZE);
+    socket.set_broadcast(true)?;
+    socket.send_to(
+        &bincode.serialize(&DiscoveryMessage::Probe(MAGIC)).unwrap(),
+        target,
+    )?;
+
+    let deadline = Instant::now() + timeout;
+    let mut servers: Vec<DiscoveredServer> = vec![];
+    let mut buffer = [0; 512];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(servers);
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let (length, sender) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return Ok(servers);
+            }
+            Err(e) => return Err(e),
+        };
+        if let Ok(DiscoveryMessage::Announce(MAGIC, info)) = bincode.deserialize(&buffer[..length])
+        {
+            let addr = SocketAddr::new(sender.ip(), info.port);
+            // Ignore repeated answers
+            if servers.iter().all(|server| server.addr != addr) {
+                servers.push(DiscoveredServer { addr, info });
+            }
+        }
+    }
+}
+
+/// Look for servers on the discovery port of this machine and the local network.
+pub fn discover_lan(timeout: Duration) -> IoResult<Vec<DiscoveredServer>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    // Servers on this machine answer both probes, prefer their loopback address
+    let mut servers = discover(&socket, ("127.0.0.1", DISCOVERY_PORT), timeout)?;
+    match discover(&socket, ("255.255.255.255", DISCOVERY_PORT), timeout) {
+        Ok(remote) => merge_broadcast(&mut servers, remote, is_own_address),
+        Err(e) => warn!("Broadcast discovery failed: {}", e),
+    }
+    Ok(servers)
+}
+
+/// Add the answers to the broadcast probe to the servers found before, leaving out those already
+/// known and the servers of this machine, which answer both probes.
+fn merge_broadcast<F: Fn(IpAddr) -> bool>(
+    servers: &mut Vec<DiscoveredServer>,
+    remote: Vec<DiscoveredServer>,
+    is_own_address: F,
+) {
+    for server in remote {
+        let known = servers.iter().any(|known| {
+            known.addr == server.addr
+                || (known.addr.ip().is_loopback()
+                    && known.addr.port() == server.addr.port()
+                    && is_own_address(server.addr.ip()))
+        });
+        if !known {
+            servers.push(server);
+        }
+    }
+}
+
+/// Return whether the address belongs to a network interface of this machine.
+///
+/// Packets to an own address are sent from that same address, so the source address the system
+/// chooses for it tells whether it is one.
+fn is_own_address(ip: IpAddr) -> bool {
+    if ip.is_loopback() {
+        return true;
+    }
+    UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+            socket.connect((ip, DISCOVERY_PORT))?;
+            socket.local_addr()
+        })
+        .is_ok_and(|local| local.ip() == ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_discover() {
+        //! A server answers a probe sent over loopback.
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        let info = ServerInfo {
+            name: "test".to_string(),
+            port: 1234,
+            players: 2,
+            state: MatchState::Running,
+        };
+        let served_info = info.clone();
+        thread::spawn(move || serve(&server_socket, || served_info.clone()));
+
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let servers = discover(&client_socket, server_addr, Duration::from_millis(500)).unwrap();
+        assert_eq!(
+            servers,
+            vec![DiscoveredServer {
+                addr: "127.0.0.1:1234".parse().unwrap(),
+                info,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_merge_broadcast() {
+        //! Identical servers on different machines are all listed, the own server only once.
+        let info = ServerInfo {
+            name: "rpsrtsrs".to_string(),
+            port: 8080,
+            players: 0,
+            state: MatchState::Lobby,
+        };
+        let server = |addr: &str| DiscoveredServer {
+            addr: addr.parse().unwrap(),
+            info: info.clone(),
+        };
+        let mut servers = vec![server("127.0.0.1:8080")];
+        let own = "192.168.1.2".parse::<IpAddr>().unwrap();
+        merge_broadcast(
+            &mut servers,
+            vec![
+                server("192.168.1.2:8080"),
+                server("192.168.1.3:8080"),
+                server("192.168.1.4:8080"),
+                server("192.168.1.3:8080"),
+            ],
+            |ip| ip == own,
+        );
+        assert_eq!(
+            servers,
+            vec![
+                server("127.0.0.1:8080"),
+                server("192.168.1.3:8080"),
+                server("192.168.1.4:8080"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_own_address() {
+        assert!(is_own_address("127.0.0.1".parse().unwrap()));
+        assert!(!is_own_address("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ignore_foreign_traffic() {
+        //! Messages that aren't probes don't get an answer.
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        thread::spawn(move || {
+            serve(&server_socket, || ServerInfo {
+                name: String::new(),
+                port: 0,
+                players: 0,
+                state: MatchState::Lobby,
+            })
+        });
+
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client_socket.send_to(b"hello", server_addr).unwrap();
+        client_socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut buffer = [0; 512];
+        assert!(client_socket.recv_from(&mut buffer).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! Formations for groups of units.
+//!
+//! When a group of units is sent to the same point, every unit gets its own slot in a formation
+//! around that point, so that the units don't pile up on top of each other.
+use cgmath::InnerSpace;
+
+use crate::common::Vec2;
+use crate::state::UNIT_SIZE;
+
+/// Distance in m between the centers of neighbouring slots
+pub const SPACING: f64 = 2.5 * UNIT_SIZE;
+
+/// The shape a group of units takes on.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone, Hash, Default)]
+pub enum Formation {
+    /// Square block of units
+    #[default]
+    Box,
+    /// All units side by side, perpendicular to the direction of movement
+    Line,
+    /// Arrow head with the tip pointing in the direction of movement
+    Wedge,
+}
+
+impl Formation {
+    pub fn next(self) -> Formation {
+        match self {
+            Formation::Box => Formation::Line,
+            Formation::Line => Formation::Wedge,
+            Formation::Wedge => Formation::Box,
+        }
+    }
+
+    /// Return the offsets of `n` slots relative to the center of the formation.
+    ///
+    /// The formation faces the positive x axis.
+    pub fn offsets(self, n: usize) -> Vec<Vec2> {
+        let offsets: Vec<Vec2> = match self {
+            Formation::Box => grid_offsets(n),
+            Formation::Line => (0..n).map(|i| Vec2::new(0.0, i as f64 * SPACING)).collect(),
+            Formation::Wedge => (0..n)
+                .map(|i| {
+                    let row = i.div_ceil(2);
+                    let side = if i % 2 == 0 { 1.0 } else { -1.0 };
+                    Vec2::new(-(row as f64), side * row as f64) * SPACING
+                })
+                .collect(),
+        };
+        if offsets.is_empty() {
+            return offsets;
+        }
+        let center = offsets.iter().fold(Vec2::new(0.0, 0.0), |sum, p| sum + p) / n as f64;
+        offsets.into_iter().map(|offset| offset - center).collect()
+    }
+}
+
+/// Return the offsets of `n` slots on a square grid centered on the origin.
+///
+/// The grid is filled row by row, every row is perpendicular to the x axis and the first one is
+/// in front.
+pub fn grid_offsets(n: usize) -> Vec<Vec2> {
+    if n == 0 {
+        return vec![];
+    }
+    let columns = (n as f64).sqrt().ceil() as usize;
+    let rows = n.div_ceil(columns);
+    let center = Vec2::new(-(rows as f64 - 1.0), columns as f64 - 1.0) * SPACING / 2.0;
+    (0..n)
+        .map(|i| Vec2::new(-((i / columns) as f64), (i % columns) as f64) * SPACING - center)
+        .collect()
+}
+
+/// Rotate `v` by `angle` radiant.
+pub fn rotate(v: Vec2, angle: f64) -> Vec2 {
+    let (sin, cos) = angle.sin_cos();
+    Vec2::new(v.x * cos - v.y * sin, v.x * sin + v.y * cos)
+}
+
+/// Assign every unit to one of the slots.
+///
+/// Every unit first takes the closest free slot, the farthest from the group's center first.
+/// Afterwards pairs of units swap their slots as long as this shortens their paths, which keeps
+/// the paths of the units from crossing. Returns the index of the slot for every position, in
+/// the same order.
+pub fn assign(positions: &[Vec2], slots: &[Vec2]) -> Vec<usize> {
+    assert!(slots.len() >= positions.len(), "Not enough slots");
+    if positions.is_empty() {
+        return vec![];
+    }
+
+    let center =
+        positions.iter().fold(Vec2::new(0.0, 0.0), |sum, p| sum + p) / positions.len() as f64;
+    let mut order: Vec<usize> = (0..positions.len()).collect();
+    order.sort_by(|a, b| {
+        let da = (positions[*a] - center).magnitude2();
+        let db = (positions[*b] - center).magnitude2();
+        db.total_cmp(&da)
+    });
+
+    let mut free: Vec<usize> = (0..slots.len()).collect();
+    let mut assigned = vec![0; positions.len()];
+    for i in order {
+        let (index, _) = free
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                let da = (slots[**a] - positions[i]).magnitude2();
+                let db = (slots[**b] - positions[i]).magnitude2();
+                da.total_cmp(&db)
+            })
+            .unwrap();
+        assigned[i] = free.swap_remove(index);
+    }
+
+    let cost = |unit: usize, slot: usize| (slots[slot] - positions[unit]).magnitude2();
+    // Every swap strictly decreases the total cost, the limit only guards against rounding
+    for _ in 0..positions.len() * positions.len() {
+        let mut swapped = false;
+        for i in 0..positions.len() {
+            for j in i + 1..positions.len() {
+                let current = cost(i, assigned[i]) + cost(j, assigned[j]);
+                let exchanged = cost(i, assigned[j]) + cost(j, assigned[i]);
+                if exchanged < current {
+                    assigned.swap(i, j);
+                    swapped = true;
+                }
+            }
+        }
+        if !swapped {
+            break;
+        }
+    }
+    assigned
+}
+
+/// Return a slot of the formation around `target` for every unit at the given positions.
+///
+/// The formation faces from the center of the group towards the target. The result contains
+/// the slot of every unit and the index of the unit in the first slot of the formation, which
+/// can lead the group.
+pub fn distribute(positions: &[Vec2], target: Vec2, formation: Formation) -> (Vec<Vec2>, usize) {
+    if positions.is_empty() {
+        return (vec![], 0);
+    }
+    let center =
+        positions.iter().fold(Vec2::new(0.0, 0.0), |sum, p| sum + p) / positions.len() as f64;
+    let direction = target - center;
+    let facing = if direction.magnitude2() > 0.0 {
+        direction.y.atan2(direction.x)
+    } else {
+        0.0
+    };
+
+    let slots: Vec<Vec2> = formation
+        .offsets(positions.len())
+        .into_iter()
+        .map(|offset| target + rotate(offset, facing))
+        .collect();
+    let assigned = assign(positions, &slots);
+    let leader = assigned.iter().position(|slot| *slot == 0).unwrap_or(0);
+    (
+        assigned.into_iter().map(|slot| slots[slot]).collect(),
+        leader,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grid_offsets() {
+        //! The grid is centered on the origin and neighbouring slots are `SPACING` apart.
+        assert!(grid_offsets(0).is_empty());
+        assert_eq!(grid_offsets(1), vec![Vec2::new(0.0, 0.0)]);
+
+        let offsets = grid_offsets(4);
+        let sum = offsets.iter().fold(Vec2::new(0.0, 0.0), |sum, p| sum + p);
+        assert_eq!(sum, Vec2::new(0.0, 0.0));
+        assert_eq!((offsets[1] - offsets[0]).magnitude(), SPACING);
+        assert_eq!((offsets[2] - offsets[0]).magnitude(), SPACING);
+    }
+
+    #[test]
+    fn test_formation_offsets() {
+        //! Lines are perpendicular to the facing and wedges point forward.
+        let line = Formation::Line.offsets(3);
+        assert!(line.iter().all(|offset| offset.x == 0.0));
+        assert_eq!(line[1], Vec2::new(0.0, 0.0));
+
+        let wedge = Formation::Wedge.offsets(5);
+        assert!(wedge[1..].iter().all(|offset| offset.x < wedge[0].x));
+        assert_eq!(wedge[1].x, wedge[2].x);
+        assert_eq!(wedge[1].y, -wedge[2].y);
+    }
+
+    #[test]
+    fn test_distribute() {
+        //! Every unit gets its own target and units keep their relative arrangement.
+        let positions = vec![Vec2::new(0.0, 0.0), Vec2::new(1000.0, 0.0)];
+        let (targets, _) = distribute(&positions, Vec2::new(500.0, 500.0), Formation::Box);
+
+        assert_eq!(targets.len(), 2);
+        assert_ne!(targets[0], targets[1]);
+        assert!(targets[0].x < targets[1].x);
+        assert_eq!((targets[0] + targets[1]) / 2.0, Vec2::new(500.0, 500.0));
+    }
+
+    #[test]
+    fn test_distribute_facing() {
+        //! A line moving north stretches from west to east.
+        let positions = vec![
+            Vec2::new(0.0, 1000.0),
+            Vec2::new(10.0, 1000.0),
+            Vec2::new(20.0, 1000.0),
+        ];
+        let (targets, leader) = distribute(&positions, Vec2::new(10.0, 0.0), Formation::Line);
+
+        let epsilon = 1e-9;
+        assert!(targets.iter().all(|target| target.y.abs() < epsilon));
+        assert!(targets[0].x < targets[1].x && targets[1].x < targets[2].x);
+        assert!(leader < positions.len());
+    }
+}
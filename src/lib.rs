@@ -10,10 +10,15 @@ extern crate log;
 #[cfg(feature = "include_sdl2")]
 extern crate sdl2_window;
 
+pub mod chat;
 pub mod client;
 pub mod colors;
 pub mod common;
+pub mod discovery;
+pub mod formation;
+pub mod lobby;
 pub mod network;
+pub mod replay;
 pub mod server;
 pub mod shapes;
 pub mod state;
@@ -0,0 +1,232 @@
+//! State of the lobby, where players gather and get ready before a match starts.
+
+use crate::colors;
+use crate::state::ClientId;
+
+pub const MAX_UNITS_PER_PLAYER: usize = 20;
+
+pub const MAX_TEAMS: usize = 8;
+
+/// Every player needs a color of their own
+pub const MAX_PLAYERS: usize = colors::PLAYERS.len();
+
+/// Settings of the match chosen by the host.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct MatchSettings {
+    /// Number of units every player starts with
+    pub units_per_player: usize,
+}
+
+impl Default for MatchSettings {
+    fn default() -> MatchSettings {
+        MatchSettings {
+            units_per_player: 4,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct LobbyPlayer {
+    pub id: ClientId,
+    /// Index of the player's color in `colors::PLAYERS`
+    pub color: usize,
+    /// Players with the same team share the team chat
+    pub team: usize,
+    pub ready: bool,
+}
+
+/// Players waiting for the match to start. This is sent to the clients while the match hasn't
+/// started yet.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct LobbyState {
+    pub players: Vec<LobbyPlayer>,
+    /// The player allowed to change the settings, the first one that joined
+    pub host: Option<ClientId>,
+    pub settings: MatchSettings,
+}
+
+impl LobbyState {
+    pub fn new() -> LobbyState {
+        LobbyState::default()
+    }
+
+    pub fn player(&self, id: ClientId) -> Option<&LobbyPlayer> {
+        self.players.iter().find(|player| player.id == id)
+    }
+
+    fn player_mut(&mut self, id: ClientId) -> Option<&mut LobbyPlayer> {
+        self.players.iter_mut().find(|player| player.id == id)
+    }
+
+    fn color_used(&self, color: usize) -> bool {
+        self.players.iter().any(|player| player.color == color)
+    }
+
+    /// Add a player with the first free color. Joining twice has no effect. Return whether the
+    /// player is in the lobby, i.e. false if it is full.
+    pub fn join(&mut self, id: ClientId) -> bool {
+        if self.player(id).is_some() {
+            return true;
+        }
+        let color = match (0..MAX_PLAYERS).find(|color| !self.color_used(*color)) {
+            Some(color) => color,
+            None => return false,
+        };
+        // Everyone starts in a team of their own, as far as there are teams
+        let team = (0..MAX_TEAMS)
+            .find(|team| self.players.iter().all(|player| player.team != *team))
+            .unwrap_or(0);
+        self.players.push(LobbyPlayer {
+            id,
+            color,
+            team,
+            ready: false,
+        });
+        if self.host.is_none() {
+            self.host = Some(id);
+        }
+        true
+    }
+
+    /// Remove a player, handing the host role to the next player if necessary.
+    pub fn leave(&mut self, id: ClientId) {
+        self.players.retain(|player| player.id != id);
+        if self.host == Some(id) {
+            self.host = self.players.first().map(|player| player.id);
+        }
+    }
+
+    pub fn set_ready(&mut self, id: ClientId, ready: bool) {
+        if let Some(player) = self.player_mut(id) {
+            player.ready = ready;
+        }
+    }
+
+    /// Change the color of a player, if no one else uses it. Return whether it was changed.
+    pub fn set_color(&mut self, id: ClientId, color: usize) -> bool {
+        if color >= colors::PLAYERS.len() || self.color_used(color) {
+            return false;
+        }
+        match self.player_mut(id) {
+            Some(player) => {
+                player.color = color;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_team(&mut self, id: ClientId, team: usize) {
+        if team >= MAX_TEAMS {
+            return;
+        }
+        if let Some(player) = self.player_mut(id) {
+            player.team = team;
+        }
+    }
+
+    /// Change the match settings if the player is the host. Return whether they were changed.
+    pub fn set_settings(&mut self, id: ClientId, settings: MatchSettings) -> bool {
+        if self.host != Some(id) {
+            return false;
+        }
+        self.apply_settings(settings);
+        true
+    }
+
+    /// Change the match settings without checking who asked for it, e.g. for the server admin.
+    pub fn apply_settings(&mut self, settings: MatchSettings) {
+        self.settings = MatchSettings {
+            units_per_player: settings.units_per_player.clamp(1, MAX_UNITS_PER_PLAYER),
+        };
+        // Players have to agree to the new settings
+        for player in self.players.iter_mut() {
+            player.ready = false;
+        }
+    }
+
+    /// Return whether the match can start, i.e. there are players and all of them are ready.
+    pub fn all_ready(&self) -> bool {
+        !self.players.is_empty() && self.players.iter().all(|player| player.ready)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_and_leave() {
+        //! Players get distinct colors and teams, the host role moves on when the host leaves.
+        let mut lobby = LobbyState::new();
+        lobby.join(ClientId(1));
+        lobby.join(ClientId(2));
+        lobby.join(ClientId(1));
+        assert_eq!(lobby.players.len(), 2);
+        assert_eq!(lobby.player(ClientId(2)).unwrap().color, 1);
+        assert_eq!(lobby.host, Some(ClientId(1)));
+
+        assert_eq!(lobby.player(ClientId(2)).unwrap().team, 1);
+        lobby.set_team(ClientId(2), 0);
+        lobby.set_team(ClientId(2), MAX_TEAMS);
+        assert_eq!(lobby.player(ClientId(2)).unwrap().team, 0);
+
+        lobby.leave(ClientId(1));
+        assert_eq!(lobby.host, Some(ClientId(2)));
+        lobby.leave(ClientId(2));
+        assert_eq!(lobby.host, None);
+    }
+
+    #[test]
+    fn test_full_lobby() {
+        //! Players beyond the number of colors are turned away.
+        let mut lobby = LobbyState::new();
+        for id in 0..MAX_PLAYERS as u32 {
+            assert!(lobby.join(ClientId(id)));
+        }
+        assert!(!lobby.join(ClientId(100)));
+        assert_eq!(lobby.players.len(), MAX_PLAYERS);
+        assert!(lobby
+            .players
+            .iter()
+            .all(|player| player.color < MAX_PLAYERS));
+        assert!(lobby.join(ClientId(0)), "already in the lobby");
+
+        lobby.leave(ClientId(2));
+        assert!(lobby.join(ClientId(100)));
+        assert_eq!(lobby.player(ClientId(100)).unwrap().color, 2);
+    }
+
+    #[test]
+    fn test_colors() {
+        let mut lobby = LobbyState::new();
+        lobby.join(ClientId(1));
+        lobby.join(ClientId(2));
+        assert!(!lobby.set_color(ClientId(2), 0), "color is taken");
+        assert!(!lobby.set_color(ClientId(2), colors::PLAYERS.len()));
+        assert!(lobby.set_color(ClientId(2), 2));
+        assert_eq!(lobby.player(ClientId(2)).unwrap().color, 2);
+    }
+
+    #[test]
+    fn test_ready() {
+        //! The match starts when everyone is ready, changed settings need to be confirmed again.
+        let mut lobby = LobbyState::new();
+        assert!(!lobby.all_ready());
+        lobby.join(ClientId(1));
+        lobby.join(ClientId(2));
+        lobby.set_ready(ClientId(1), true);
+        assert!(!lobby.all_ready());
+        lobby.set_ready(ClientId(2), true);
+        assert!(lobby.all_ready());
+
+        let settings = MatchSettings {
+            units_per_player: 100,
+        };
+        assert!(!lobby.set_settings(ClientId(2), settings.clone()));
+        assert!(lobby.all_ready());
+        assert!(lobby.set_settings(ClientId(1), settings));
+        assert_eq!(lobby.settings.units_per_player, MAX_UNITS_PER_PLAYER);
+        assert!(!lobby.all_ready());
+    }
+}
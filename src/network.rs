@@ -3,19 +3,58 @@
 //! Everything related to the network protocol between the sever and the
 //! clients.
 
+use crate::chat::{ChatChannel, ChatMessage};
 use crate::common::Vec2;
-use crate::state::{ClientId, GameState, UnitId, WorldState};
+use crate::formation::Formation;
+use crate::lobby::{LobbyState, MatchSettings};
+use crate::state::{ClientId, GameState, Stance, UnitId, WorldState};
+
+/// Upper limit in bytes for a single serialized message.
+pub const MAX_MESSAGE_SIZE: u64 = 64 * 1024;
 
 /// Commands alter the game state.
 ///
 /// A command is sent from the client to the server. Examples include the
-/// movement of a unit or the decision to attack another unit.
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+/// movement of a unit or the decision to attack another unit. Every command
+/// addresses a group of units, so that a whole selection can be ordered with
+/// a single message.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub enum Command {
-    /// Move command with unit ID and target
-    Move(UnitId, Vec2),
-    /// Let the unit shoot
-    Shoot(UnitId),
+    /// Move command with unit IDs, target and the formation of the group
+    Move(Vec<UnitId>, Vec2, Formation),
+    /// Append a move to the target in formation to the orders of the units
+    Waypoint(Vec<UnitId>, Vec2, Formation),
+    /// Let the units shoot
+    Shoot(Vec<UnitId>),
+    /// Let the units chase and shoot at another unit
+    Attack(Vec<UnitId>, UnitId),
+    /// Move the units to the target while engaging all enemies in range on the way
+    AttackMove(Vec<UnitId>, Vec2, Formation),
+    /// Change how the units react to enemies while they have no order
+    SetStance(Vec<UnitId>, Stance),
+    /// Cancel the current orders of the units
+    Stop(Vec<UnitId>),
+    /// Let the units stay where they are and only shoot at enemies in range
+    HoldPosition(Vec<UnitId>),
+    /// Let the units patrol between their current positions and the given waypoints
+    Patrol(Vec<UnitId>, Vec<Vec2>),
+}
+
+impl Command {
+    /// Return the units the command is addressed to.
+    pub fn units(&self) -> &[UnitId] {
+        match self {
+            Command::Move(ids, _, _)
+            | Command::Waypoint(ids, _, _)
+            | Command::Shoot(ids)
+            | Command::Attack(ids, _)
+            | Command::AttackMove(ids, _, _)
+            | Command::SetStance(ids, _)
+            | Command::Stop(ids)
+            | Command::HoldPosition(ids)
+            | Command::Patrol(ids, _) => ids,
+        }
+    }
 }
 
 /// Primary message type sent between server and client.
@@ -26,7 +65,31 @@ pub enum Message {
     Error,
     ClientHello,
     ClientReconnect(ClientId),
+    /// Watch the match without a player, receiving the unfiltered game state
+    SpectatorHello,
     ServerHello(ClientId, WorldState),
     UpdateGamestate(GameState),
     Command(Command),
+    /// Latency measurement sent by the client, with a client defined timestamp
+    Ping(u64),
+    /// Answer to a `Ping`, echoing its timestamp
+    Pong(u64),
+    /// State of the lobby, sent instead of the game state until the match starts
+    LobbyUpdate(LobbyState),
+    /// The player is ready to start the match, or not anymore
+    Ready(bool),
+    /// The player wants to play with the color of the given index
+    ChooseColor(usize),
+    /// Change of the match settings, only accepted from the host
+    ChangeSettings(MatchSettings),
+    /// The player wants to join the team with the given index
+    ChooseTeam(usize),
+    /// Chat message sent by a client
+    Chat(ChatChannel, String),
+    /// Chat message delivered by the server
+    ChatMessage(ChatMessage),
+    /// The player wants to pause (`true`) or resume the game
+    Pause(bool),
+    /// Change of the game speed, only accepted from the host
+    SetSpeed(f64),
 }
@@ -0,0 +1,294 @@
+//! Recording of matches on the server and their playback.
+//!
+//! A replay consists of the state at the start of the match and every event that influenced it
+//! afterwards, tagged with the tick it happened in. Because the random numbers of the simulation
+//! only depend on the seed and the tick, running the simulation with the same events again
+//! reproduces the match.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+
+use bincode::Options;
+
+use crate::lobby::MatchSettings;
+use crate::network::Command;
+use crate::server;
+use crate::state::{ClientId, GameState, Player, Unit, WorldState};
+
+/// Version of the file format, replays of other versions can't be played
+const REPLAY_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ReplayHeader {
+    pub version: u32,
+    pub world: WorldState,
+    pub settings: MatchSettings,
+    /// Seed of the simulation's random numbers
+    pub seed: u64,
+    /// State when the match started
+    pub initial: GameState,
+}
+
+impl ReplayHeader {
+    pub fn new(
+        world: WorldState,
+        settings: MatchSettings,
+        seed: u64,
+        initial: GameState,
+    ) -> ReplayHeader {
+        ReplayHeader {
+            version: REPLAY_VERSION,
+            world,
+            settings,
+            seed,
+            initial,
+        }
+    }
+}
+
+/// Something that changed the course of the match.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum ReplayEvent {
+    /// A command the server received from a player
+    Command(ClientId, Command),
+    /// A player joined the running match with the given units
+    Join(Player),
+    /// The game speed was changed
+    Speed(f64),
+    /// The server admin gave units to a player
+    Spawn(ClientId, Vec<Unit>),
+    /// The server admin removed a player from the match
+    Kick(ClientId),
+}
+
+/// Writes a replay file while the match is running.
+///
+/// Every event is written right away, so the replay survives the server being killed.
+#[derive(Debug)]
+pub struct Recorder {
+    file: File,
+    started: bool,
+}
+
+impl Recorder {
+    /// Create the replay file, nothing is written until the match starts.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Recorder> {
+        Ok(Recorder {
+            file: File::create(path)?,
+            started: false,
+        })
+    }
+
+    fn write<T: serde::Serialize>(&mut self, value: &T) -> Result<(), Box<dyn Error>> {
+        let encoded = bincode::DefaultOptions::new().serialize(value)?;
+        self.file.write_all(&encoded)?;
+        Ok(())
+    }
+
+    /// Write the header. Only the first match of the server is recorded.
+    pub fn start(&mut self, header: &ReplayHeader) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+        if let Err(e) = self.write(header) {
+            error!("Could not write replay: {}", e);
+        }
+    }
+
+    /// Write an event that happened before the simulation step of the given tick.
+    pub fn record(&mut self, tick: u64, event: &ReplayEvent) {
+        if !self.started {
+            return;
+        }
+        if let Err(e) = self.write(&(tick, event)) {
+            error!("Could not write replay: {}", e);
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    /// Events in the order they happened, with their tick
+    pub events: Vec<(u64, ReplayEvent)>,
+}
+
+impl Replay {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Replay, Box<dyn Error>> {
+        let bincode = bincode::DefaultOptions::new();
+        let mut reader = BufReader::new(File::open(path)?);
+        let header: ReplayHeader = bincode.deserialize_from(&mut reader)?;
+        if header.version != REPLAY_VERSION {
+            return Err(format!("Unsupported replay version {}", header.version).into());
+        }
+        let mut events = vec![];
+        loop {
+            match bincode.deserialize_from(&mut reader) {
+                Ok(event) => events.push(event),
+                // The last event may be cut off if the server was stopped while writing it
+                Err(e) => match *e {
+                    bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        break
+                    }
+                    _ => return Err(e),
+                },
+            }
+        }
+        Ok(Replay { header, events })
+    }
+
+    /// Return the tick of the last event.
+    pub fn last_tick(&self) -> u64 {
+        self.events.last().map_or(0, |(tick, _)| *tick)
+    }
+}
+
+/// Runs the simulation of a replay.
+#[derive(Debug, Clone)]
+pub struct Playback {
+    replay: Replay,
+    game: GameState,
+    /// Index of the next event to apply
+    next_event: usize,
+}
+
+impl Playback {
+    pub fn new(replay: Replay) -> Playback {
+        Playback {
+            game: replay.header.initial.clone(),
+            replay,
+            next_event: 0,
+        }
+    }
+
+    pub fn world(&self) -> &WorldState {
+        &self.replay.header.world
+    }
+
+    pub fn game(&self) -> &GameState {
+        &self.game
+    }
+
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+
+    /// Apply the events of the current tick and run one simulation step.
+    pub fn step(&mut self) {
+        let header = &self.replay.header;
+        while let Some((tick, event)) = self.replay.events.get(self.next_event) {
+            if *tick > self.game.tick {
+                break;
+            }
+            match event {
+                ReplayEvent::Command(client_id, command) => server::handle_command(
+                    &header.world,
+                    &mut self.game,
+                    *client_id,
+                    command,
+                    header.seed,
+                ),
+                ReplayEvent::Join(player) => self.game.players.push(player.clone()),
+                ReplayEvent::Speed(speed) => self.game.set_speed(*speed),
+                ReplayEvent::Spawn(id, units) => {
+                    if let Some(player) = self.game.player_mut(*id) {
+                        player.units.extend(units.iter().cloned());
+                    }
+                }
+                ReplayEvent::Kick(id) => self.game.players.retain(|player| player.id != *id),
+            }
+            self.next_event += 1;
+        }
+        server::step(&header.world, &mut self.game, header.seed);
+    }
+
+    /// Jump to the given tick. Going back restarts the simulation from the beginning.
+    pub fn seek(&mut self, tick: u64) {
+        if tick < self.game.tick {
+            self.game = self.replay.header.initial.clone();
+            self.next_event = 0;
+        }
+        while self.game.tick < tick {
+            self.step();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{TempPath, Vec2};
+    use crate::formation::Formation;
+    use crate::state::{MatchState, Unit, UnitId};
+
+    fn initial_state() -> GameState {
+        let mut game = GameState::new();
+        game.match_state = MatchState::Running;
+        for id in 0..2 {
+            let mut player = Player::new(id);
+            player
+                .units
+                .push(Unit::new(id, Vec2::new(100.0 + 200.0 * id as f64, 100.0)));
+            game.players.push(player);
+        }
+        game
+    }
+
+    #[test]
+    fn test_record_and_play() {
+        //! Playing the recorded events reproduces the match, seeking back and forth included.
+        let world = WorldState::new(800.0, 600.0);
+        let seed = 42;
+        let path = TempPath::new("replay");
+        let mut recorder = Recorder::create(&path).unwrap();
+        let mut game = initial_state();
+        recorder.start(&ReplayHeader::new(
+            world.clone(),
+            MatchSettings::default(),
+            seed,
+            game.clone(),
+        ));
+
+        // Run the match like the server does, recording the commands
+        let commands = [
+            (10, Command::Shoot(vec![UnitId(0)])),
+            (
+                20,
+                Command::Move(
+                    vec![UnitId(1)],
+                    Vec2::new(500.0, 400.0),
+                    Formation::default(),
+                ),
+            ),
+        ];
+        for tick in 0..100 {
+            for (command_tick, command) in commands.iter() {
+                if *command_tick == tick {
+                    let owner = ClientId(command.units()[0].0);
+                    server::handle_command(&world, &mut game, owner, command, seed);
+                    recorder.record(tick, &ReplayEvent::Command(owner, command.clone()));
+                }
+            }
+            server::step(&world, &mut game, seed);
+        }
+        drop(recorder);
+
+        let replay = Replay::load(&path).unwrap();
+        assert_eq!(replay.events.len(), 2);
+        assert_eq!(replay.last_tick(), 20);
+
+        let mut playback = Playback::new(replay.clone());
+        playback.seek(100);
+        assert_eq!(*playback.game(), game);
+
+        let mut fresh = Playback::new(replay);
+        fresh.seek(15);
+        playback.seek(15);
+        assert_eq!(playback.game(), fresh.game());
+        playback.seek(100);
+        assert_eq!(*playback.game(), game);
+    }
+}
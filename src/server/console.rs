@@ -0,0 +1,303 @@
+//! Commands typed into the terminal the server runs in.
+
+use std::fs;
+use std::io::{self, BufRead};
+use std::net::{Shutdown, SocketAddr};
+use std::str::FromStr;
+
+use crate::lobby::{MatchSettings, MAX_UNITS_PER_PLAYER};
+use crate::replay::ReplayEvent;
+use crate::state::{ClientId, MatchState};
+
+use super::{record, spawn_units, start_match, Server};
+
+const HELP: &str = "Commands:
+    players         List the players and spectators
+    kick ID         Remove the player from the match and close the connection
+    ban ID          Kick the player and refuse further connections from its address
+    pause           Pause the match
+    resume          Resume the match
+    units COUNT     Set the units per player, only in the lobby
+    spawn ID COUNT  Give the player additional units
+    dump [FILE]     Show the game state as JSON, or write it to the file
+    save FILE       Save the running match, continue it later with --load FILE
+    help            Show this help";
+
+/// Execute a command line and return the text to show to the admin.
+pub fn execute(server: &Server, line: &str) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let result = match words[..] {
+        [] => Ok(String::new()),
+        ["players"] => Ok(players(server)),
+        ["kick", id] => parse(id).map(|id| kick(server, ClientId(id), false)),
+        ["ban", id] => parse(id).map(|id| kick(server, ClientId(id), true)),
+        ["pause"] => set_paused(server, true),
+        ["resume"] => set_paused(server, false),
+        ["units", count] => parse(count).and_then(|count| set_units(server, count)),
+        ["spawn", id, count] => parse(id)
+            .and_then(|id| parse(count).map(|count| (ClientId(id), count)))
+            .and_then(|(id, count)| spawn(server, id, count)),
+        ["dump"] => dump(server, None),
+        ["dump", path] => dump(server, Some(path)),
+        ["save", path] => server
+            .save_game(path)
+            .map(|_| format!("Saved the match to {}", path))
+            .map_err(|e| format!("Could not save the match: {}", e)),
+        ["help"] => Ok(HELP.to_string()),
+        _ => Err(format!(
+            "Unknown command or wrong arguments '{}', try 'help'",
+            line.trim()
+        )),
+    };
+    result.unwrap_or_else(|e| e)
+}
+
+fn parse<T: FromStr>(word: &str) -> Result<T, String> {
+    word.parse()
+        .map_err(|_| format!("'{}' is not a valid number", word))
+}
+
+fn players(server: &Server) -> String {
+    let game = server.game.lock().unwrap();
+    let lobby = server.lobby.lock().unwrap();
+    let mut lines: Vec<String> = match game.match_state {
+        MatchState::Lobby => lobby
+            .players
+            .iter()
+            .map(|player| {
+                format!(
+                    "{}: color {}, team {}, {}",
+                    player.id,
+                    player.color,
+                    player.team + 1,
+                    if player.ready { "ready" } else { "not ready" }
+                )
+            })
+            .collect(),
+        MatchState::Running => game
+            .players
+            .iter()
+            .map(|player| {
+                format!(
+                    "{}: color {}, team {}, {} units",
+                    player.id,
+                    player.color,
+                    player.team + 1,
+                    player.units.len()
+                )
+            })
+            .collect(),
+    };
+    // Connections of clients that are neither in the lobby nor in the game
+    for (address, (id, _)) in server.connections.lock().unwrap().iter() {
+        if game.player(*id).is_none() && lobby.player(*id).is_none() {
+            lines.push(format!("{}: spectator from {}", id, address));
+        }
+    }
+    if lines.is_empty() {
+        "No players".to_string()
+    } else {
+        lines.join("\n")
+    }
+}
+
+/// Remove the client from the lobby and the match and close its connections. Banning also
+/// refuses new connections from the addresses it was connected from.
+fn kick(server: &Server, id: ClientId, ban: bool) -> String {
+    let (was_player, started) = {
+        let mut game = server.game.lock().unwrap();
+        let mut lobby = server.lobby.lock().unwrap();
+        let in_game = game.player(id).is_some();
+        let in_lobby = lobby.player(id).is_some();
+        game.players.retain(|player| player.id != id);
+        lobby.leave(id);
+        if in_game {
+            record(&server.replay, game.tick, ReplayEvent::Kick(id));
+        }
+        // The kicked player may have been the last one the others waited for
+        let started = game.match_state == MatchState::Lobby && lobby.all_ready();
+        if started {
+            start_match(&server.world, &mut game, &lobby, &server.unit_id_generator);
+        }
+        (in_game || in_lobby, started)
+    };
+    if started {
+        server.start_recording();
+    }
+
+    let mut connections = server.connections.lock().unwrap();
+    let addresses: Vec<SocketAddr> = connections
+        .iter()
+        .filter(|(_, (client, _))| *client == id)
+        .map(|(address, _)| *address)
+        .collect();
+    for address in addresses.iter() {
+        if let Some((_, stream)) = connections.remove(address) {
+            // Both threads of the client stop once their stream fails
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        if ban {
+            server.banned.lock().unwrap().insert(address.ip());
+        }
+    }
+
+    if !was_player && addresses.is_empty() {
+        return format!("No client with ID {}", id);
+    }
+    let mut output = format!("Kicked {}, closed {} connection(s)", id, addresses.len());
+    if ban {
+        let banned: Vec<String> = addresses.iter().map(|a| a.ip().to_string()).collect();
+        output.push_str(&format!(", banned: {}", banned.join(", ")));
+    }
+    output
+}
+
+fn set_paused(server: &Server, paused: bool) -> Result<String, String> {
+    let mut game = server.game.lock().unwrap();
+    if game.match_state != MatchState::Running {
+        return Err("The match hasn't started yet".to_string());
+    }
+    game.paused = paused;
+    game.pause_votes.clear();
+    Ok(if paused { "Paused" } else { "Resumed" }.to_string())
+}
+
+fn set_units(server: &Server, count: usize) -> Result<String, String> {
+    let game = server.game.lock().unwrap();
+    if game.match_state != MatchState::Lobby {
+        return Err("Settings can only be changed in the lobby".to_string());
+    }
+    let mut lobby = server.lobby.lock().unwrap();
+    lobby.apply_settings(MatchSettings {
+        units_per_player: count,
+    });
+    Ok(format!(
+        "Units per player: {}",
+        lobby.settings.units_per_player
+    ))
+}
+
+fn spawn(server: &Server, id: ClientId, count: usize) -> Result<String, String> {
+    let mut game = server.game.lock().unwrap();
+    let index = game
+        .players
+        .iter()
+        .position(|player| player.id == id)
+        .ok_or(format!("No player with ID {} in the match", id))?;
+    let mut player = game.players[index].clone();
+    let before = player.units.len();
+    spawn_units(
+        &server.world,
+        &game,
+        &mut player,
+        count.min(MAX_UNITS_PER_PLAYER),
+        &server.unit_id_generator,
+    );
+    let units = player.units[before..].to_vec();
+    record(
+        &server.replay,
+        game.tick,
+        ReplayEvent::Spawn(id, units.clone()),
+    );
+    game.players[index] = player;
+    Ok(format!("Spawned {} units for player {}", units.len(), id))
+}
+
+fn dump(server: &Server, path: Option<&str>) -> Result<String, String> {
+    let json = serde_json::to_string_pretty(&*server.game.lock().unwrap())
+        .map_err(|e| format!("Could not serialize the game state: {}", e))?;
+    match path {
+        Some(path) => fs::write(path, json)
+            .map(|_| format!("Wrote the game state to {}", path))
+            .map_err(|e| format!("Could not write {}: {}", path, e)),
+        None => Ok(json),
+    }
+}
+
+/// Read commands from the standard input until it is closed.
+pub fn run(server: Server) {
+    for line in io::stdin().lock().lines() {
+        match line {
+            Ok(line) => {
+                let output = execute(&server, &line);
+                if !output.is_empty() {
+                    println!("{}", output);
+                }
+            }
+            Err(e) => {
+                error!("Could not read from the console: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Player;
+
+    #[test]
+    fn test_execute() {
+        let server = Server::new(("127.0.0.1", 0), (800.0, 600.0)).unwrap();
+        assert_eq!(execute(&server, "  "), "");
+        assert!(execute(&server, "foo bar").starts_with("Unknown command"));
+        assert!(execute(&server, "save").starts_with("Unknown command"));
+        assert_eq!(execute(&server, "help"), HELP);
+        assert_eq!(execute(&server, "kick x"), "'x' is not a valid number");
+        assert_eq!(execute(&server, "players"), "No players");
+    }
+
+    #[test]
+    fn test_lobby_commands() {
+        let server = Server::new(("127.0.0.1", 0), (800.0, 600.0)).unwrap();
+        server.lobby.lock().unwrap().join(ClientId(1));
+        assert_eq!(execute(&server, "players"), "1: color 0, team 1, not ready");
+        assert_eq!(execute(&server, "units 100"), "Units per player: 20");
+        assert_eq!(execute(&server, "pause"), "The match hasn't started yet");
+        assert_eq!(
+            execute(&server, "kick 1"),
+            "Kicked 1, closed 0 connection(s)"
+        );
+        assert_eq!(execute(&server, "kick 1"), "No client with ID 1");
+    }
+
+    #[test]
+    fn test_kick_starts_match() {
+        //! Kicking the only player that isn't ready starts the match for the others.
+        let server = Server::new(("127.0.0.1", 0), (800.0, 600.0)).unwrap();
+        {
+            let mut lobby = server.lobby.lock().unwrap();
+            lobby.join(ClientId(1));
+            lobby.join(ClientId(2));
+            lobby.set_ready(ClientId(1), true);
+        }
+        execute(&server, "kick 2");
+        let game = server.game.lock().unwrap();
+        assert_eq!(game.match_state, MatchState::Running);
+        assert_eq!(game.players.len(), 1);
+        assert!(game.player(ClientId(1)).is_some());
+    }
+
+    #[test]
+    fn test_match_commands() {
+        let server = Server::new(("127.0.0.1", 0), (800.0, 600.0)).unwrap();
+        {
+            let mut game = server.game.lock().unwrap();
+            game.match_state = MatchState::Running;
+            game.players.push(Player::new(1));
+        }
+        assert_eq!(
+            execute(&server, "spawn 1 3"),
+            "Spawned 3 units for player 1"
+        );
+        assert!(execute(&server, "spawn 2 3").starts_with("No player"));
+        assert_eq!(execute(&server, "pause"), "Paused");
+        assert!(server.game.lock().unwrap().paused);
+        assert!(execute(&server, "units 2").starts_with("Settings can only"));
+
+        let dump = execute(&server, "dump");
+        let game: crate::state::GameState = serde_json::from_str(&dump).unwrap();
+        assert_eq!(game.players[0].units.len(), 3);
+    }
+}
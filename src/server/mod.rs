@@ -1,37 +1,65 @@
 use rand;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::f64::consts::PI;
 use std::io::Result as IoResult;
 use std::io::Write;
-use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
 use std::ops::Deref;
 use std::ops::RangeFrom;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
 use bincode::Options;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
+use crate::chat::{ChatChannel, ChatLog};
 use crate::common::Vec2;
-use crate::network::{Command, Message};
+use crate::discovery::{self, ServerInfo};
+use crate::formation::{self, Formation};
+use crate::lobby::LobbyState;
+use crate::network::{Command, Message, MAX_MESSAGE_SIZE};
+use crate::replay::{Recorder, ReplayEvent, ReplayHeader};
 use crate::shapes::Shape;
-use crate::state::{GameState, Player, Unit, UnitId, WorldState, UNIT_SIZE};
-use num::clamp;
+use crate::state::{
+    ClientId, GameState, MatchState, Order, Player, Unit, UnitId, WeaponKind, WorldState, UNIT_SIZE,
+};
+
+pub mod console;
+pub mod save;
+
+/// Real time between two simulation steps in milli seconds
+pub const UPDATE_INTERVAL_MS: f64 = 5.0;
 
 /// A `Server` instance holds global server state.
+///
+/// Clones share the state, every client thread works on its own clone.
+#[derive(Clone)]
 pub struct Server {
     socket_addr: SocketAddr,
     world: Arc<WorldState>,
     game: Arc<Mutex<GameState>>,
+    lobby: Arc<Mutex<LobbyState>>,
+    chat: Arc<Mutex<ChatLog>>,
     /// Generator that returns sequential unit IDs
     unit_id_generator: Arc<Mutex<RangeFrom<u32>>>,
     /// Generator that returns sequential client IDs
     client_id_generator: Arc<Mutex<RangeFrom<u32>>>,
-
-    /// Map with active unit move commands
-    unit_targets: Arc<Mutex<HashMap<UnitId, Vec2>>>,
+    /// Name announced to clients looking for servers
+    name: String,
+    /// UDP port to answer discovery probes on, if any
+    discovery_port: Option<u16>,
+    /// Seed of the simulation's random numbers
+    seed: u64,
+    /// Writes the replay of the match, if one is recorded
+    replay: Arc<Mutex<Option<Recorder>>>,
+    /// Open connections by the address of the client, so that the admin can close them
+    connections: Arc<Mutex<HashMap<SocketAddr, (ClientId, TcpStream)>>>,
+    /// Addresses new connections are refused from
+    banned: Arc<Mutex<HashSet<IpAddr>>>,
 }
 
 impl Server {
@@ -43,41 +71,112 @@ impl Server {
             socket_addr: addr,
             world,
             game,
+            lobby: Arc::new(Mutex::new(LobbyState::new())),
+            chat: Arc::new(Mutex::new(ChatLog::new())),
             client_id_generator: Arc::new(Mutex::new(0..)),
             unit_id_generator: Arc::new(Mutex::new(0..)),
-            unit_targets: Arc::new(Mutex::new(HashMap::new())),
+            name: "rpsrtsrs".to_string(),
+            discovery_port: Some(discovery::DISCOVERY_PORT),
+            seed: rand::random(),
+            replay: Arc::new(Mutex::new(None)),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            banned: Arc::new(Mutex::new(HashSet::new())),
         })
     }
 
+    pub fn set_name<T: Into<String>>(&mut self, name: T) {
+        self.name = name.into();
+    }
+
+    /// Set the port to answer discovery probes on, `None` disables discovery.
+    pub fn set_discovery_port(&mut self, port: Option<u16>) {
+        self.discovery_port = port;
+    }
+
+    /// Record the match into a replay file at the given path.
+    ///
+    /// Recording starts with the match, or right away if a loaded match is already running.
+    pub fn set_replay_file<P: AsRef<Path>>(&mut self, path: P) -> IoResult<()> {
+        *self.replay.lock().unwrap() = Some(Recorder::create(path)?);
+        self.start_recording();
+        Ok(())
+    }
+
+    /// Write the replay header if the match is running. Does nothing if no replay is recorded or
+    /// the recording already started.
+    fn start_recording(&self) {
+        let game = self.game.lock().unwrap();
+        if game.match_state != MatchState::Running {
+            return;
+        }
+        let lobby = self.lobby.lock().unwrap();
+        if let Some(recorder) = self.replay.lock().unwrap().as_mut() {
+            recorder.start(&ReplayHeader::new(
+                self.world.deref().clone(),
+                lobby.settings.clone(),
+                self.seed,
+                game.clone(),
+            ));
+        }
+    }
+
+    /// Answer discovery probes in the background.
+    fn announce(&self, port: u16) {
+        let socket = match UdpSocket::bind(("0.0.0.0", port)) {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("Could not listen for discovery probes: {}", e);
+                return;
+            }
+        };
+        let game = self.game.clone();
+        let lobby = self.lobby.clone();
+        let name = self.name.clone();
+        let game_port = self.socket_addr.port();
+        thread::spawn(move || {
+            discovery::serve(&socket, || {
+                let game_lock = game.lock().unwrap();
+                let players = match game_lock.match_state {
+                    MatchState::Lobby => lobby.lock().unwrap().players.len(),
+                    MatchState::Running => game_lock.players.len(),
+                };
+                ServerInfo {
+                    name: name.clone(),
+                    port: game_port,
+                    players,
+                    state: game_lock.match_state,
+                }
+            })
+        });
+    }
+
     pub fn serve(&self) {
         let tcp_listener = TcpListener::bind(self.socket_addr).unwrap();
         info!("Start server: {:?}", tcp_listener);
+        if let Some(port) = self.discovery_port {
+            self.announce(port);
+        }
 
         let game_clone = self.game.clone();
-        let unit_targets_clone = self.unit_targets.clone();
         let world_clone = self.world.clone();
+        let seed = self.seed;
         thread::spawn(move || {
-            update_world(world_clone, game_clone, unit_targets_clone);
+            update_world(world_clone, game_clone, seed);
         });
 
         for stream in tcp_listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    let world_clone = self.world.clone();
-                    let game_clone = self.game.clone();
-                    let client_id_generator_clone = self.client_id_generator.clone();
-                    let unit_id_generator_clone = self.unit_id_generator.clone();
-                    let unit_targets = self.unit_targets.clone();
+                    if let Ok(peer) = stream.peer_addr() {
+                        if self.banned.lock().unwrap().contains(&peer.ip()) {
+                            info!("Refusing banned address {}", peer);
+                            continue;
+                        }
+                    }
+                    let server = self.clone();
                     info!("Spawning thread...");
                     thread::spawn(move || {
-                        handle_client(
-                            stream,
-                            world_clone,
-                            game_clone,
-                            client_id_generator_clone,
-                            unit_id_generator_clone,
-                            unit_targets,
-                        );
+                        handle_client(stream, server);
                     });
                 }
                 Err(e) => {
@@ -88,26 +187,166 @@ impl Server {
     }
 }
 
-pub type SafeUnitTargets = Arc<Mutex<HashMap<UnitId, Vec2>>>;
+/// Create `count` units at random positions that don't collide with any other unit.
+fn spawn_units(
+    world: &WorldState,
+    game: &GameState,
+    player: &mut Player,
+    count: usize,
+    unit_id_generator: &Mutex<RangeFrom<u32>>,
+) {
+    let mut rng = rand::thread_rng();
+    for i in 0..count {
+        let unit_id = unit_id_generator
+            .lock()
+            .expect("Could not lock unit_id_generator mutex")
+            .next()
+            .expect("No more unit IDs available!");
 
-pub fn handle_client(
-    mut stream: TcpStream,
-    world: Arc<WorldState>,
-    game: Arc<Mutex<GameState>>,
-    client_id_generator: Arc<Mutex<RangeFrom<u32>>>,
-    unit_id_generator: Arc<Mutex<RangeFrom<u32>>>,
-    unit_targets: SafeUnitTargets,
+        // Try 42 times to create a new unit
+        'outer: for _ in 0..42 {
+            let position = Vec2::new(rng.gen::<f64>() * world.x, rng.gen::<f64>() * world.y);
+            let mut new_unit = Unit::new(unit_id, position);
+            new_unit.weapon = WeaponKind::ALL[i % WeaponKind::ALL.len()];
+            for player in &game.players {
+                for unit in &player.units {
+                    if unit.collision_detect(&new_unit, UNIT_SIZE) {
+                        continue 'outer;
+                    }
+                }
+            }
+            for unit in &player.units {
+                if unit.collision_detect(&new_unit, UNIT_SIZE) {
+                    continue 'outer;
+                }
+            }
+            // check if collision
+            player.units.push(new_unit);
+            break;
+        }
+    }
+}
+
+/// Create the players of the lobby with their units and start the match.
+fn start_match(
+    world: &WorldState,
+    game: &mut GameState,
+    lobby: &LobbyState,
+    unit_id_generator: &Mutex<RangeFrom<u32>>,
+) {
+    info!("Starting match with {} players", lobby.players.len());
+    for lobby_player in lobby.players.iter() {
+        let mut player = Player::new(lobby_player.id);
+        player.color = lobby_player.color;
+        player.team = lobby_player.team;
+        spawn_units(
+            world,
+            game,
+            &mut player,
+            lobby.settings.units_per_player,
+            unit_id_generator,
+        );
+        game.players.push(player);
+    }
+    game.match_state = MatchState::Running;
+}
+
+/// Handle a message sent by a client in the lobby.
+fn handle_lobby_message(
+    world: &WorldState,
+    game: &mut GameState,
+    lobby: &mut LobbyState,
+    client_id: ClientId,
+    message: Message,
+    unit_id_generator: &Mutex<RangeFrom<u32>>,
+) {
+    if game.match_state != MatchState::Lobby {
+        info!(
+            "Ignoring lobby message after the match started: {:?}",
+            message
+        );
+        return;
+    }
+    match message {
+        Message::Ready(ready) => lobby.set_ready(client_id, ready),
+        Message::ChooseColor(color) => {
+            lobby.set_color(client_id, color);
+        }
+        Message::ChangeSettings(settings) => {
+            lobby.set_settings(client_id, settings);
+        }
+        Message::ChooseTeam(team) => lobby.set_team(client_id, team),
+        _ => error!("Not a lobby message: {:?}", message),
+    }
+    if lobby.all_ready() {
+        start_match(world, game, lobby, unit_id_generator);
+    }
+}
+
+/// Write an event into the replay, if one is recorded.
+fn record(replay: &Mutex<Option<Recorder>>, tick: u64, event: ReplayEvent) {
+    if let Some(recorder) = replay.lock().unwrap().as_mut() {
+        recorder.record(tick, &event);
+    }
+}
+
+/// Post a chat message to the players of the lobby or the match, depending on the phase.
+fn post_chat(
+    game: &GameState,
+    lobby: &LobbyState,
+    chat: &mut ChatLog,
+    from: ClientId,
+    channel: ChatChannel,
+    text: &str,
 ) {
+    let teams: Vec<(ClientId, usize)> = match game.match_state {
+        MatchState::Lobby => lobby
+            .players
+            .iter()
+            .map(|player| (player.id, player.team))
+            .collect(),
+        MatchState::Running => game
+            .players
+            .iter()
+            .map(|player| (player.id, player.team))
+            .collect(),
+    };
+    let players: Vec<ClientId> = teams.iter().map(|(id, _)| *id).collect();
+    let team = |id: ClientId| {
+        teams
+            .iter()
+            .find(|(player, _)| *player == id)
+            .map(|(_, team)| *team)
+    };
+    chat.post(from, channel, text, &players, team);
+}
+
+pub fn handle_client(mut stream: TcpStream, server: Server) {
+    let Server {
+        world,
+        game,
+        lobby,
+        chat,
+        client_id_generator,
+        unit_id_generator,
+        seed,
+        replay,
+        connections,
+        ..
+    } = server;
     // handle client hello
-    let bincode = bincode::DefaultOptions::new().with_limit(1024);
+    let bincode = bincode::DefaultOptions::new().with_limit(MAX_MESSAGE_SIZE);
     let client_message = bincode.deserialize_from(&mut stream);
     info!("Received: {:?}", client_message);
-    match client_message {
+    // Spectators see everything, but can't influence the match
+    let spectator = matches!(client_message, Ok(Message::SpectatorHello));
+    let client_id = match client_message {
         Ok(message) => {
             match message {
                 Message::ClientHello => {
                     // Get exclusive world access
                     let mut game_lock = game.lock().unwrap();
+                    let mut lobby_lock = lobby.lock().unwrap();
 
                     // Create new player for the newly connected client
                     let client_id = client_id_generator
@@ -115,72 +354,82 @@ pub fn handle_client(
                         .expect("Could not lock client_id_generator mutex")
                         .next()
                         .expect("No more client IDs available!");
-                    let mut player = Player::new(client_id);
-
-                    let mut rng = rand::thread_rng();
-                    for _ in 0..4 {
-                        let unit_id = unit_id_generator
-                            .lock()
-                            .expect("Could not lock unit_id_generator mutex")
-                            .next()
-                            .expect("No more unit IDs available!");
-
-                        // Try 42 times to create a new unit
-                        'outer: for _ in 0..42 {
-                            let position =
-                                Vec2::new(rng.gen::<f64>() * world.x, rng.gen::<f64>() * world.y);
-                            let new_unit = Unit::new(unit_id, position);
-                            for player in &game_lock.players {
-                                for unit in &player.units {
-                                    if unit.collision_detect(&new_unit, UNIT_SIZE) {
-                                        continue 'outer;
-                                    }
-                                }
-                            }
-                            for unit in &player.units {
-                                if unit.collision_detect(&new_unit, UNIT_SIZE) {
-                                    continue 'outer;
-                                }
-                            }
-                            // check if collision
-                            player.units.push(new_unit);
-                            break;
-                        }
-                    }
 
-                    // Add player to the world
-                    let player_id = player.id;
-                    game_lock.players.push(player);
+                    let joined = if game_lock.match_state == MatchState::Lobby {
+                        // The player gets units when the match starts
+                        lobby_lock.join(ClientId(client_id))
+                    } else if let (Some(color), Some(team)) =
+                        (game_lock.unused_color(), game_lock.unused_team())
+                    {
+                        let mut player = Player::new(client_id);
+                        player.color = color;
+                        player.team = team;
+                        spawn_units(
+                            &world,
+                            &game_lock,
+                            &mut player,
+                            lobby_lock.settings.units_per_player,
+                            &unit_id_generator,
+                        );
+                        record(&replay, game_lock.tick, ReplayEvent::Join(player.clone()));
+                        game_lock.players.push(player);
+                        true
+                    } else {
+                        false
+                    };
+                    if !joined {
+                        error!("No room for another player");
+                        let encoded: Vec<u8> = bincode.serialize(&Message::Error).unwrap();
+                        stream.write_all(&encoded).unwrap();
+                        return; // Don't enter game loop
+                    }
+                    let player_id = ClientId(client_id);
 
                     // Send ServerHello message
                     let encoded: Vec<u8> = bincode
                         .serialize(&Message::ServerHello(player_id, world.deref().clone()))
                         .unwrap();
                     stream.write_all(&encoded).unwrap();
+                    player_id
+                }
+                Message::SpectatorHello => {
+                    let client_id = client_id_generator
+                        .lock()
+                        .expect("Could not lock client_id_generator mutex")
+                        .next()
+                        .expect("No more client IDs available!");
+                    let spectator_id = ClientId(client_id);
+                    info!("Spectator {} connected", spectator_id);
+
+                    // Send ServerHello message
+                    let encoded: Vec<u8> = bincode
+                        .serialize(&Message::ServerHello(spectator_id, world.deref().clone()))
+                        .unwrap();
+                    stream.write_all(&encoded).unwrap();
+                    spectator_id
                 }
                 Message::ClientReconnect(id) => {
                     // Get exclusive world access
                     let game_lock = game.lock().unwrap();
+                    let lobby_lock = lobby.lock().unwrap();
 
                     // Find player with specified id
-                    match game_lock.players.iter().find(|player| player.id == id) {
-                        Some(_) => {
-                            info!("Found you :)");
-
-                            // Send ServerHello message
-                            let encoded: Vec<u8> = bincode
-                                .serialize(&Message::ServerHello(id, world.deref().clone()))
-                                .unwrap();
-                            stream.write_all(&encoded).unwrap();
-                        }
-                        None => {
-                            error!("Reconnect to id {} not possible", id);
+                    if game_lock.player(id).is_some() || lobby_lock.player(id).is_some() {
+                        info!("Found you :)");
 
-                            // Send Error message
-                            let encoded: Vec<u8> = bincode.serialize(&Message::Error).unwrap();
-                            stream.write_all(&encoded).unwrap();
-                            return; // Don't enter game loop
-                        }
+                        // Send ServerHello message
+                        let encoded: Vec<u8> = bincode
+                            .serialize(&Message::ServerHello(id, world.deref().clone()))
+                            .unwrap();
+                        stream.write_all(&encoded).unwrap();
+                        id
+                    } else {
+                        error!("Reconnect to id {} not possible", id);
+
+                        // Send Error message
+                        let encoded: Vec<u8> = bincode.serialize(&Message::Error).unwrap();
+                        stream.write_all(&encoded).unwrap();
+                        return; // Don't enter game loop
                     }
                 }
                 _ => {
@@ -195,44 +444,151 @@ pub fn handle_client(
             error!("Error: {:?}", e);
             return; // Don't enter game loop
         }
+    };
+
+    let peer = stream.peer_addr().ok();
+    if let (Some(peer), Ok(clone)) = (peer, stream.try_clone()) {
+        connections.lock().unwrap().insert(peer, (client_id, clone));
     }
 
     let mut command_stream = stream.try_clone().unwrap();
+    // Both loops write to the client, complete messages must not be interleaved
+    let writer = Arc::new(Mutex::new(stream));
+    let command_writer = writer.clone();
     let game_clone = game.clone();
+    let lobby_clone = lobby.clone();
+    let chat_clone = chat.clone();
     // Command receiver loop
     thread::spawn(move || loop {
         let client_message: Result<Message, _> = bincode.deserialize_from(&mut command_stream);
         info!("{:?}", client_message);
         match client_message {
+            Ok(Message::Ping(timestamp)) => {
+                let encoded: Vec<u8> = bincode.serialize(&Message::Pong(timestamp)).unwrap();
+                if let Err(e) = command_writer.lock().unwrap().write_all(&encoded) {
+                    error!("Error: {:?}", e);
+                    return;
+                }
+            }
+            Ok(message) if spectator => {
+                info!("Ignoring message of spectator {}: {:?}", client_id, message);
+            }
             Ok(message) => match message {
                 Message::Command(command) => {
                     let mut game_lock = game_clone.lock().unwrap();
-                    let mut unit_targets_lock = unit_targets.lock().unwrap();
-                    handle_command(&world, &mut game_lock, &mut unit_targets_lock, &command);
+                    handle_command(&world, &mut game_lock, client_id, &command, seed);
+                    record(
+                        &replay,
+                        game_lock.tick,
+                        ReplayEvent::Command(client_id, command),
+                    );
+                }
+                Message::Ready(_)
+                | Message::ChooseColor(_)
+                | Message::ChangeSettings(_)
+                | Message::ChooseTeam(_) => {
+                    let mut game_lock = game_clone.lock().unwrap();
+                    let mut lobby_lock = lobby_clone.lock().unwrap();
+                    let in_lobby = game_lock.match_state == MatchState::Lobby;
+                    handle_lobby_message(
+                        &world,
+                        &mut game_lock,
+                        &mut lobby_lock,
+                        client_id,
+                        message,
+                        &unit_id_generator,
+                    );
+                    if in_lobby && game_lock.match_state == MatchState::Running {
+                        if let Some(recorder) = replay.lock().unwrap().as_mut() {
+                            recorder.start(&ReplayHeader::new(
+                                world.deref().clone(),
+                                lobby_lock.settings.clone(),
+                                seed,
+                                game_lock.clone(),
+                            ));
+                        }
+                    }
+                }
+                Message::Pause(paused) => {
+                    let mut game_lock = game_clone.lock().unwrap();
+                    let host = lobby_clone.lock().unwrap().host;
+                    game_lock.request_pause(client_id, paused, host);
+                    info!(
+                        "Pause: {} (votes: {:?})",
+                        game_lock.paused, game_lock.pause_votes
+                    );
+                }
+                Message::SetSpeed(speed) => {
+                    let mut game_lock = game_clone.lock().unwrap();
+                    if lobby_clone.lock().unwrap().host == Some(client_id) {
+                        game_lock.set_speed(speed);
+                        info!("Game speed: {}", game_lock.speed);
+                        record(&replay, game_lock.tick, ReplayEvent::Speed(game_lock.speed));
+                    }
+                }
+                Message::Chat(channel, text) => {
+                    let game_lock = game_clone.lock().unwrap();
+                    let lobby_lock = lobby_clone.lock().unwrap();
+                    post_chat(
+                        &game_lock,
+                        &lobby_lock,
+                        &mut chat_clone.lock().unwrap(),
+                        client_id,
+                        channel,
+                        &text,
+                    );
                 }
                 _ => {
                     error!("Did receive unexpected message: {:?}", message);
                     let encoded: Vec<u8> = bincode.serialize(&Message::Error).unwrap();
-                    command_stream.write_all(&encoded).unwrap();
+                    let _ = command_writer.lock().unwrap().write_all(&encoded);
                     return;
                 }
             },
             Err(e) => {
                 error!("Error: {:?}", e);
+                // Players that leave the lobby don't hold up the others
+                let game_lock = game_clone.lock().unwrap();
+                if game_lock.match_state == MatchState::Lobby && !spectator {
+                    lobby_clone.lock().unwrap().leave(client_id);
+                }
                 return;
             }
         };
     });
 
     // GameState loop
+    let mut chat_sequence = chat.lock().unwrap().next_sequence();
     loop {
         let encoded: Vec<u8> = {
+            let mut encoded = vec![];
+            {
+                let chat_lock = chat.lock().unwrap();
+                for message in chat_lock.messages_for(client_id, chat_sequence) {
+                    encoded.extend(encode_update(bincode, &Message::ChatMessage(message)));
+                }
+                chat_sequence = chat_lock.next_sequence();
+            }
             let game_lock = game.lock().unwrap();
-            bincode.serialize(&*game_lock).unwrap()
+            let message = if game_lock.match_state == MatchState::Lobby {
+                Message::LobbyUpdate(lobby.lock().unwrap().clone())
+            } else {
+                if spectator {
+                    Message::UpdateGamestate(game_lock.clone())
+                } else {
+                    Message::UpdateGamestate(game_lock.visible_to(client_id))
+                }
+            };
+            encoded.extend(encode_update(bincode, &message));
+            encoded
         };
-        match stream.write_all(&encoded) {
+        let result = writer.lock().unwrap().write_all(&encoded);
+        match result {
             Err(e) => {
                 error!("Error: {:?}", e);
+                if let Some(peer) = peer {
+                    connections.lock().unwrap().remove(&peer);
+                }
                 return;
             }
             _ => thread::sleep(Duration::from_millis(10)),
@@ -240,53 +596,273 @@ pub fn handle_client(
     }
 }
 
+/// Serialize a message for a client, or return nothing if it exceeds the message size limit, so
+/// that a grown state skips updates instead of disconnecting everyone.
+fn encode_update<O: Options>(bincode: O, message: &Message) -> Vec<u8> {
+    match bincode.serialize(message) {
+        Ok(encoded) => encoded,
+        Err(e) => {
+            error!("Skipping update: {}", e);
+            vec![]
+        }
+    }
+}
+
+fn group_positions(game: &GameState, ids: &[UnitId]) -> Vec<(UnitId, Vec2)> {
+    ids.iter()
+        .filter_map(|id| game.find_unit(*id).map(|(_, unit)| (*id, unit.position)))
+        .collect()
+}
+
+/// Return a slot of the formation around `target` for every unit of the group, clamped to the
+/// world, together with the index of the unit that leads the formation.
+fn group_targets(
+    world: &WorldState,
+    game: &GameState,
+    ids: &[UnitId],
+    target: Vec2,
+    formation: Formation,
+) -> (Vec<(UnitId, Vec2)>, usize) {
+    let group = group_positions(game, ids);
+    let positions: Vec<Vec2> = group.iter().map(|(_, position)| *position).collect();
+    let (targets, leader) = formation::distribute(&positions, world.clamp(target), formation);
+    let targets = group
+        .iter()
+        .zip(targets)
+        .map(|((id, _), target)| (*id, world.clamp(target)))
+        .collect();
+    (targets, leader)
+}
+
+/// Return orders that move the group to `target` while keeping the formation.
+fn formation_orders(
+    world: &WorldState,
+    game: &GameState,
+    ids: &[UnitId],
+    target: Vec2,
+    formation: Formation,
+) -> Vec<(UnitId, Order)> {
+    let (targets, leader) = group_targets(world, game, ids, target, formation);
+    let (leader, leader_target) = match targets.get(leader) {
+        Some(leader) => *leader,
+        None => return vec![],
+    };
+    targets
+        .iter()
+        .map(|(id, target)| {
+            let order = Order::FormationMove {
+                target: *target,
+                leader,
+                offset: target - leader_target,
+            };
+            (*id, order)
+        })
+        .collect()
+}
+
 pub fn handle_command(
     world: &WorldState,
     game: &mut GameState,
-    unit_targets: &mut HashMap<UnitId, Vec2>,
+    client_id: ClientId,
     command: &Command,
+    seed: u64,
 ) {
-    info!("Did receive command {:?}", command);
+    info!("Did receive command {:?} from {}", command, client_id);
+    // Clients may only command their own units
+    let ids = &game.owned_units(client_id, command.units());
     match command {
-        Command::Move(id, move_target) => {
-            for player in game.players.iter_mut() {
-                for unit in player.units.iter_mut() {
-                    if unit.id == *id {
-                        info!("Found it :)");
-                        let mut target = Vec2::new(0.0, 0.0);
-                        target.x = clamp(move_target.x, 0.0, world.x);
-                        target.y = clamp(move_target.y, 0.0, world.y);
-                        let dx = target.x - unit.position.x;
-                        let dy = target.y - unit.position.y;
-                        if dx.is_sign_negative() {
-                            unit.angle = (dy / dx).atan() + PI;
-                        } else {
-                            unit.angle = (dy / dx).atan();
+        Command::Move(_, move_target, formation) => {
+            if let [id] = ids[..] {
+                // A single unit doesn't need a formation
+                if let Some(unit) = game.find_unit_mut(id) {
+                    let target = world.clamp(*move_target);
+                    let dx = target.x - unit.position.x;
+                    let dy = target.y - unit.position.y;
+                    if dx.is_sign_negative() {
+                        unit.angle = (dy / dx).atan() + PI;
+                    } else {
+                        unit.angle = (dy / dx).atan();
+                    }
+                    unit.order(Order::Move(target));
+                }
+            } else {
+                for (id, order) in formation_orders(world, game, ids, *move_target, *formation) {
+                    if let Some(unit) = game.find_unit_mut(id) {
+                        unit.order(order);
+                    }
+                }
+            }
+            info!("Move {:?} to {:?} in {:?}!", ids, move_target, formation);
+        }
+        Command::Waypoint(_, move_target, formation) => {
+            for (id, order) in formation_orders(world, game, ids, *move_target, *formation) {
+                if let Some(unit) = game.find_unit_mut(id) {
+                    unit.orders.push_back(order);
+                }
+            }
+            info!(
+                "Queue move of {:?} to {:?} in {:?}!",
+                ids, move_target, formation
+            );
+        }
+        Command::Shoot(_) => {
+            let mut rng = simulation_rng(seed, game.tick);
+            for id in ids {
+                game.shoot(*id, &mut rng);
+            }
+        }
+        Command::Attack(_, target_id) => {
+            // Only visible units can be attacked, or clients could find hidden units by guessing
+            // their IDs and watching where their own units go
+            let target_owner = game
+                .find_unit(*target_id)
+                .filter(|(_, target)| game.is_visible_to(client_id, target.position))
+                .map(|(owner, _)| owner);
+            for id in ids {
+                let owner = game.find_unit(*id).map(|(owner, _)| owner);
+                match (owner, target_owner) {
+                    (Some(owner), Some(target_owner)) if owner != target_owner => {
+                        if let Some(unit) = game.find_unit_mut(*id) {
+                            unit.order(Order::Attack(*target_id));
                         }
-                        unit_targets.insert(*id, target);
+                        info!("Unit {} attacks {}!", id, target_id);
                     }
+                    _ => warn!("Unit {} can't attack {}", id, target_id),
+                }
+            }
+        }
+        Command::AttackMove(_, move_target, formation) => {
+            let (targets, _) = group_targets(world, game, ids, *move_target, *formation);
+            for (id, target) in targets {
+                if let Some(unit) = game.find_unit_mut(id) {
+                    unit.order(Order::AttackMove(target));
                 }
             }
-            info!("Move {} to {:?}!", id, move_target);
+            info!("Attack-move {:?} to {:?}!", ids, move_target);
         }
-        Command::Shoot(id) => {
-            game.shoot(*id);
+        Command::SetStance(_, stance) => {
+            for id in ids {
+                if let Some(unit) = game.find_unit_mut(*id) {
+                    unit.stance = *stance;
+                }
+            }
+        }
+        Command::Stop(_) => {
+            for id in ids {
+                if let Some(unit) = game.find_unit_mut(*id) {
+                    unit.orders.clear();
+                }
+            }
+            info!("Stop {:?}!", ids);
+        }
+        Command::HoldPosition(_) => {
+            for id in ids {
+                if let Some(unit) = game.find_unit_mut(*id) {
+                    unit.order(Order::HoldPosition);
+                }
+            }
+            info!("Hold position {:?}!", ids);
+        }
+        Command::Patrol(_, patrol_targets) => {
+            if let Some(first) = patrol_targets.first() {
+                // Every unit keeps its offset to the group's first waypoint on the whole route
+                let (targets, _) = group_targets(world, game, ids, *first, Formation::default());
+                for (id, target) in targets {
+                    let offset = target - world.clamp(*first);
+                    if let Some(unit) = game.find_unit_mut(id) {
+                        let mut waypoints = vec![unit.position];
+                        waypoints.extend(
+                            patrol_targets
+                                .iter()
+                                .map(|waypoint| world.clamp(waypoint + offset)),
+                        );
+                        unit.order(Order::Patrol(waypoints, 1));
+                    }
+                }
+            }
+            info!("Patrol {:?} along {:?}!", ids, patrol_targets);
         }
     }
 }
 
-pub fn update_world(
-    world: Arc<WorldState>,
-    game: Arc<Mutex<GameState>>,
-    unit_targets: SafeUnitTargets,
-) {
+/// Return the random number generator for the given tick of the simulation.
+///
+/// The numbers only depend on the seed and the tick, so that replays can reproduce them.
+pub fn simulation_rng(seed: u64, tick: u64) -> StdRng {
+    StdRng::seed_from_u64(seed ^ tick.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+}
+
+/// Run one simulation step, i.e. advance the game by one tick.
+pub fn step(world: &WorldState, game: &mut GameState, seed: u64) {
+    let dt = UPDATE_INTERVAL_MS * game.speed;
+    let mut rng = simulation_rng(seed, game.tick);
+    game.update_orders(dt, &mut rng);
+    game.update(world, dt);
+    game.tick += 1;
+}
+
+pub fn update_world(world: Arc<WorldState>, game: Arc<Mutex<GameState>>, seed: u64) {
     loop {
         {
             let mut game_lock = game.lock().unwrap();
-            let unit_targets = unit_targets.lock().unwrap();
-            game_lock.update_targets(&unit_targets);
-            game_lock.update(&world, 5.0);
+            if game_lock.is_active() {
+                step(&world, &mut game_lock, seed);
+            }
+        }
+        thread::sleep(Duration::from_millis(UPDATE_INTERVAL_MS as u64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attack_needs_visible_target() {
+        //! Attacks on units the player can't see are ignored.
+        let world = WorldState::new(5000.0, 1000.0);
+        let mut game = GameState::new();
+        game.match_state = MatchState::Running;
+        let mut player = Player::new(0);
+        player.units.push(Unit::new(0, Vec2::new(100.0, 100.0)));
+        let sight = player.units[0].sight_radius();
+        let mut enemy = Player::new(1);
+        enemy
+            .units
+            .push(Unit::new(1, Vec2::new(100.0 + sight - 1.0, 100.0)));
+        enemy
+            .units
+            .push(Unit::new(2, Vec2::new(100.0 + sight + 1.0, 100.0)));
+        game.players.push(player);
+        game.players.push(enemy);
+
+        let attack = |target| Command::Attack(vec![UnitId(0)], UnitId(target));
+        handle_command(&world, &mut game, ClientId(0), &attack(2), 0);
+        assert!(game.players[0].units[0].orders.is_empty());
+        handle_command(&world, &mut game, ClientId(0), &attack(1), 0);
+        assert_eq!(
+            game.players[0].units[0].orders.front(),
+            Some(&Order::Attack(UnitId(1)))
+        );
+    }
+
+    #[test]
+    fn test_encode_oversized_update() {
+        //! States beyond the message size limit are skipped instead of panicking.
+        let bincode = bincode::DefaultOptions::new().with_limit(MAX_MESSAGE_SIZE);
+        let mut game = GameState::new();
+        let mut player = Player::new(0);
+        player.units.push(Unit::new(0, Vec2::new(100.0, 100.0)));
+        game.players.push(player);
+        let small = encode_update(bincode, &Message::UpdateGamestate(game.clone()));
+        assert!(!small.is_empty());
+
+        for id in 1..10_000 {
+            game.players[0]
+                .units
+                .push(Unit::new(id, Vec2::new(100.0, 100.0)));
         }
-        thread::sleep(Duration::from_millis(5));
+        let large = encode_update(bincode, &Message::UpdateGamestate(game));
+        assert!(large.is_empty());
     }
 }
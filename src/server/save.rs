@@ -0,0 +1,155 @@
+//! Snapshots of a match that are written to disk and resumed later.
+
+use std::error::Error;
+use std::fs;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::lobby::LobbyState;
+use crate::state::{GameState, MatchState, WorldState};
+
+use super::Server;
+
+/// Version of the file format, saves of other versions can't be loaded
+const SAVE_VERSION: u32 = 1;
+
+/// Everything the server needs to resume a match.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct SaveGame {
+    pub version: u32,
+    pub world: WorldState,
+    pub game: GameState,
+    /// The lobby, with the host and the settings
+    pub lobby: LobbyState,
+    /// Next IDs to hand out, so that new units and players don't reuse saved IDs
+    pub next_unit_id: u32,
+    pub next_client_id: u32,
+    /// Seed of the simulation's random numbers
+    pub seed: u64,
+}
+
+impl Server {
+    /// Return a snapshot of the current match.
+    pub fn snapshot(&self) -> SaveGame {
+        let game = self.game.lock().unwrap();
+        let lobby = self.lobby.lock().unwrap();
+        SaveGame {
+            version: SAVE_VERSION,
+            world: self.world.deref().clone(),
+            game: game.clone(),
+            lobby: lobby.clone(),
+            next_unit_id: self.unit_id_generator.lock().unwrap().start,
+            next_client_id: self.client_id_generator.lock().unwrap().start,
+            seed: self.seed,
+        }
+    }
+
+    /// Write a snapshot of the current match to the file.
+    ///
+    /// Only running matches are saved, a lobby would bring back players that are long gone.
+    pub fn save_game<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        if self.game.lock().unwrap().match_state == MatchState::Lobby {
+            return Err("The match hasn't started yet".into());
+        }
+        fs::write(path, serde_json::to_string(&self.snapshot())?)?;
+        Ok(())
+    }
+
+    /// Continue the match saved in the file. This has to happen before the server is started.
+    ///
+    /// The match is paused, so that the players can reconnect with their IDs before it goes on.
+    pub fn load_game<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<dyn Error>> {
+        let save: SaveGame = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if save.version != SAVE_VERSION {
+            return Err(format!("Unsupported save version {}", save.version).into());
+        }
+        self.restore(save);
+        Ok(())
+    }
+
+    fn restore(&mut self, save: SaveGame) {
+        let mut game = save.game;
+        game.paused = true;
+        game.pause_votes.clear();
+        self.world = Arc::new(save.world);
+        *self.game.lock().unwrap() = game;
+        *self.lobby.lock().unwrap() = save.lobby;
+        *self.unit_id_generator.lock().unwrap() = save.next_unit_id..;
+        *self.client_id_generator.lock().unwrap() = save.next_client_id..;
+        self.seed = save.seed;
+        self.start_recording();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{TempPath, Vec2};
+    use crate::replay::Replay;
+    use crate::state::{ClientId, Player, Unit};
+
+    #[test]
+    fn test_no_save_in_lobby() {
+        //! Lobbies aren't saved, their players wouldn't be connected after loading.
+        let server = Server::new(("127.0.0.1", 0), (1000.0, 500.0)).unwrap();
+        server.lobby.lock().unwrap().join(ClientId(1));
+        let path = TempPath::new("lobby_save");
+        assert!(server.save_game(&path).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        //! A loaded match continues with the saved state, paused, and doesn't reuse IDs.
+        let server = Server::new(("127.0.0.1", 0), (1000.0, 500.0)).unwrap();
+        {
+            let mut game = server.game.lock().unwrap();
+            game.match_state = MatchState::Running;
+            game.tick = 123;
+            let mut player = Player::new(4);
+            player.units.push(Unit::new(9, Vec2::new(10.0, 20.0)));
+            game.players.push(player);
+            server.lobby.lock().unwrap().join(ClientId(4));
+        }
+        *server.unit_id_generator.lock().unwrap() = 10..;
+        *server.client_id_generator.lock().unwrap() = 5..;
+
+        let path = TempPath::new("save");
+        server.save_game(&path).unwrap();
+        let saved = server.snapshot();
+
+        let mut loaded = Server::new(("127.0.0.1", 0), (800.0, 600.0)).unwrap();
+        loaded.load_game(&path).unwrap();
+        let snapshot = loaded.snapshot();
+        assert!(snapshot.game.paused);
+        assert_eq!(snapshot.game.players, saved.game.players);
+        assert_eq!(snapshot.game.tick, 123);
+        assert_eq!(snapshot.world, WorldState::new(1000.0, 500.0));
+        assert_eq!(snapshot.lobby.host, Some(ClientId(4)));
+        assert_eq!(loaded.unit_id_generator.lock().unwrap().next(), Some(10));
+        assert_eq!(loaded.client_id_generator.lock().unwrap().next(), Some(5));
+        assert_eq!(snapshot.seed, saved.seed);
+    }
+
+    #[test]
+    fn test_record_loaded_match() {
+        //! Recording a loaded match that is already running starts right away.
+        let server = Server::new(("127.0.0.1", 0), (800.0, 600.0)).unwrap();
+        {
+            let mut game = server.game.lock().unwrap();
+            game.match_state = MatchState::Running;
+            game.tick = 50;
+        }
+        let save_path = TempPath::new("recorded.save");
+        server.save_game(&save_path).unwrap();
+
+        let replay_path = TempPath::new("recorded.replay");
+        let mut loaded = Server::new(("127.0.0.1", 0), (800.0, 600.0)).unwrap();
+        loaded.load_game(&save_path).unwrap();
+        loaded.set_replay_file(&replay_path).unwrap();
+        let replay = Replay::load(&replay_path).unwrap();
+        assert_eq!(replay.header.initial.tick, 50);
+        assert_eq!(replay.header.seed, server.seed);
+    }
+}
@@ -1,7 +1,7 @@
 //! Shapes in the game, like units (triangles) and buildings (squares).
 use self::graphics::math;
-use self::graphics::math::{mul_scalar, square_len, sub};
-use self::graphics::types::Triangle;
+use self::graphics::math::{add, cross, dot, mul_scalar, square_len, sub};
+use self::graphics::types::{Triangle, Vec2d};
 use super::state;
 use graphics;
 use std::f64;
@@ -13,6 +13,8 @@ pub trait Shape {
 
     fn is_hit(&self, size: f64, position: Vec2) -> bool;
 
+    fn is_swept_hit(&self, size: f64, from: Vec2, to: Vec2, radius: f64) -> bool;
+
     fn collision_detect(&self, other: &Self, size: f64) -> bool;
 
     fn collision_avoidance(&self, other: &Self) -> (Vec2, Vec2);
@@ -50,6 +52,19 @@ impl Shape for state::Unit {
         math::inside_triangle(hitbox, position.into())
     }
 
+    /// Calculate whether or not this unit is hit by a circle with the given `radius` moving from
+    /// `from` to `to`.
+    ///
+    /// Unlike `is_hit` this also catches points that pass through the unit within a single step.
+    fn is_swept_hit(&self, size: f64, from: Vec2, to: Vec2, radius: f64) -> bool {
+        let hitbox = self.get_shape(size);
+        let (from, to) = (from.into(), to.into());
+        if segment_intersects_triangle(from, to, hitbox) {
+            return true;
+        }
+        (0..3).any(|i| segments_distance(from, to, hitbox[i], hitbox[(i + 1) % 3]) <= radius)
+    }
+
     fn collision_detect(&self, other: &Self, size: f64) -> bool {
         let dv = sub(self.position.into(), other.position.into());
         square_len(dv) <= (2.0 * size * 2.0 * size)
@@ -81,11 +96,82 @@ impl Shape for state::Unit {
     }
 }
 
+/// Return on which side of the line through `a` and `b` the point `p` lies.
+///
+/// The result is positive on one side, negative on the other and zero if `p` is on the line.
+fn orientation(a: Vec2d, b: Vec2d, p: Vec2d) -> f64 {
+    cross(sub(b, a), sub(p, a))
+}
+
+/// Return whether `p`, which is known to be collinear with `a` and `b`, lies on the segment.
+fn on_segment(a: Vec2d, b: Vec2d, p: Vec2d) -> bool {
+    p[0] >= a[0].min(b[0])
+        && p[0] <= a[0].max(b[0])
+        && p[1] >= a[1].min(b[1])
+        && p[1] <= a[1].max(b[1])
+}
+
+/// Return whether the segments `p1`-`p2` and `q1`-`q2` intersect (touching counts).
+pub fn segments_intersect(p1: Vec2d, p2: Vec2d, q1: Vec2d, q2: Vec2d) -> bool {
+    let d1 = orientation(q1, q2, p1);
+    let d2 = orientation(q1, q2, p2);
+    let d3 = orientation(p1, p2, q1);
+    let d4 = orientation(p1, p2, q2);
+
+    if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0))
+        && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0))
+    {
+        return true;
+    }
+
+    (d1 == 0.0 && on_segment(q1, q2, p1))
+        || (d2 == 0.0 && on_segment(q1, q2, p2))
+        || (d3 == 0.0 && on_segment(p1, p2, q1))
+        || (d4 == 0.0 && on_segment(p1, p2, q2))
+}
+
+/// Return the distance between the point `p` and the segment from `a` to `b`.
+pub fn point_segment_distance(p: Vec2d, a: Vec2d, b: Vec2d) -> f64 {
+    let ab = sub(b, a);
+    let len = square_len(ab);
+    let t = if len == 0.0 {
+        0.0
+    } else {
+        (dot(sub(p, a), ab) / len).clamp(0.0, 1.0)
+    };
+    square_len(sub(p, add(a, mul_scalar(ab, t)))).sqrt()
+}
+
+/// Return the shortest distance between the segments `p1`-`p2` and `q1`-`q2`.
+pub fn segments_distance(p1: Vec2d, p2: Vec2d, q1: Vec2d, q2: Vec2d) -> f64 {
+    if segments_intersect(p1, p2, q1, q2) {
+        return 0.0;
+    }
+    point_segment_distance(p1, q1, q2)
+        .min(point_segment_distance(p2, q1, q2))
+        .min(point_segment_distance(q1, p1, p2))
+        .min(point_segment_distance(q2, p1, p2))
+}
+
+/// Return whether the segment from `a` to `b` touches the triangle.
+///
+/// This is the case if either end point lies inside the triangle or if the segment crosses one
+/// of its edges.
+pub fn segment_intersects_triangle(a: Vec2d, b: Vec2d, triangle: Triangle) -> bool {
+    if math::inside_triangle(triangle, a) || math::inside_triangle(triangle, b) {
+        return true;
+    }
+    (0..3).any(|i| segments_intersect(a, b, triangle[i], triangle[(i + 1) % 3]))
+}
+
 #[cfg(test)]
 mod test {
     use std::f64::consts::FRAC_PI_2;
 
-    use super::{state, Shape, Vec2};
+    use super::{
+        point_segment_distance, segment_intersects_triangle, segments_distance, segments_intersect,
+        state, Shape, Vec2,
+    };
 
     #[test]
     fn test_hitbox() {
@@ -139,4 +225,121 @@ mod test {
             assert!(unit_l.collision_detect(&unit_r, 0.5));
         }
     }
+
+    #[test]
+    fn test_segments_intersect() {
+        // Crossing
+        assert!(segments_intersect(
+            [0.0, 0.0],
+            [2.0, 2.0],
+            [0.0, 2.0],
+            [2.0, 0.0]
+        ));
+        // Parallel
+        assert!(!segments_intersect(
+            [0.0, 0.0],
+            [2.0, 0.0],
+            [0.0, 1.0],
+            [2.0, 1.0]
+        ));
+        // Touching end point
+        assert!(segments_intersect(
+            [0.0, 0.0],
+            [1.0, 1.0],
+            [1.0, 1.0],
+            [2.0, 0.0]
+        ));
+        // Collinear and overlapping
+        assert!(segments_intersect(
+            [0.0, 0.0],
+            [2.0, 0.0],
+            [1.0, 0.0],
+            [3.0, 0.0]
+        ));
+        // Collinear but disjoint
+        assert!(!segments_intersect(
+            [0.0, 0.0],
+            [1.0, 0.0],
+            [2.0, 0.0],
+            [3.0, 0.0]
+        ));
+    }
+
+    #[test]
+    fn test_segment_intersects_triangle() {
+        let triangle = [[0.0, 0.0], [2.0, 0.0], [1.0, 2.0]];
+
+        // Passing through the triangle with both end points outside
+        assert!(segment_intersects_triangle(
+            [-1.0, 1.0],
+            [3.0, 1.0],
+            triangle
+        ));
+        // Ending inside
+        assert!(segment_intersects_triangle(
+            [-1.0, 1.0],
+            [1.0, 0.5],
+            triangle
+        ));
+        // Completely inside
+        assert!(segment_intersects_triangle(
+            [0.9, 0.5],
+            [1.1, 0.5],
+            triangle
+        ));
+        // Missing the triangle
+        assert!(!segment_intersects_triangle(
+            [-1.0, 3.0],
+            [3.0, 3.0],
+            triangle
+        ));
+        assert!(!segment_intersects_triangle(
+            [-2.0, 0.0],
+            [-1.0, 2.0],
+            triangle
+        ));
+    }
+
+    #[test]
+    fn test_swept_hit() {
+        //! A point that jumps over a unit within a single step must still hit it.
+        let unit = state::Unit::new(0, Vec2::new(0.0, 0.0));
+        let from = Vec2::new(-10.0, 0.0);
+        let to = Vec2::new(10.0, 0.0);
+
+        assert!(!unit.is_hit(1.0, from));
+        assert!(!unit.is_hit(1.0, to));
+        assert!(unit.is_swept_hit(1.0, from, to, 0.0));
+        assert!(!unit.is_swept_hit(1.0, Vec2::new(-10.0, 5.0), Vec2::new(10.0, 5.0), 0.0));
+    }
+
+    #[test]
+    fn test_swept_hit_radius() {
+        //! A large projectile hits a unit it passes closely, a point-sized one doesn't.
+        let unit = state::Unit::new(0, Vec2::new(0.0, 0.0));
+        let from = Vec2::new(-10.0, 2.0);
+        let to = Vec2::new(10.0, 2.0);
+
+        assert!(!unit.is_swept_hit(1.0, from, to, 0.0));
+        assert!(unit.is_swept_hit(1.0, from, to, 2.0));
+    }
+
+    #[test]
+    fn test_segments_distance() {
+        let epsilon = 1e-9;
+        assert!(
+            (point_segment_distance([0.0, 1.0], [-1.0, 0.0], [1.0, 0.0]) - 1.0).abs() < epsilon
+        );
+        assert!(
+            (point_segment_distance([3.0, 0.0], [-1.0, 0.0], [1.0, 0.0]) - 2.0).abs() < epsilon
+        );
+        assert!(
+            (segments_distance([0.0, 1.0], [0.0, 3.0], [-1.0, 0.0], [1.0, 0.0]) - 1.0).abs()
+                < epsilon
+        );
+        assert_eq!(
+            segments_distance([0.0, -1.0], [0.0, 1.0], [-1.0, 0.0], [1.0, 0.0]),
+            0.0
+        );
+    }
 }
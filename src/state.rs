@@ -2,11 +2,17 @@
 //!
 //! All these structures should be serializable, so that they can be
 //! transferred from the server to the client over the network.
-use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::convert::Into;
+use std::f64::consts::PI;
 use std::fmt;
 
+use cgmath::InnerSpace;
+use num::clamp;
+use rand::Rng;
+
 use crate::common::Vec2;
+use crate::lobby::{MAX_PLAYERS, MAX_TEAMS};
 use crate::shapes::Shape;
 
 /// A unit identifier.
@@ -25,8 +31,21 @@ impl fmt::Display for UnitId {
     }
 }
 
+/// Phase of a match.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub enum MatchState {
+    /// Players are joining and getting ready
+    #[default]
+    Lobby,
+    Running,
+}
+
+/// Slowest and fastest game speed, as a multiple of the normal speed
+pub const MIN_GAME_SPEED: f64 = 0.25;
+pub const MAX_GAME_SPEED: f64 = 4.0;
+
 /// A client/player identifier.
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone, Hash)]
 pub struct ClientId(pub u32);
 
 impl From<u32> for ClientId {
@@ -43,6 +62,124 @@ impl fmt::Display for ClientId {
 
 pub const UNIT_SIZE: f64 = 50.0;
 
+/// Distance in m units see beyond the range of their weapon
+pub const SIGHT_MARGIN: f64 = 100.0;
+
+/// Health of a newly created unit
+pub const MAX_HEALTH: u64 = 100_000;
+
+/// Factor between the distance to the target and the speed of a moving unit, in 1 per milli second
+pub const UNIT_SPEED: f64 = 0.0001;
+
+/// Maximum rotation speed of a unit in radiant per milli second
+pub const TURN_SPEED: f64 = 0.005;
+
+/// Maximum deviation in radiant between the angle of a unit and its target to open fire
+pub const AIM_TOLERANCE: f64 = 0.05;
+
+/// Factor between the distance of a unit to its slot in a formation and its correction speed, in
+/// 1 per milli second
+pub const FORMATION_SPEED: f64 = 5.0 * UNIT_SPEED;
+
+/// Distance in m at which a unit counts as having reached a waypoint
+pub const ARRIVAL_DISTANCE: f64 = UNIT_SIZE;
+
+/// The properties of a weapon.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Weapon {
+    /// Damage dealt to a unit on every hit
+    pub damage: u64,
+
+    /// Speed of the projectiles in m per milli second
+    pub speed: f64,
+
+    /// Distance in m a projectile travels before it expires
+    pub range: f64,
+
+    /// Maximum deviation of a projectile from the aiming angle in radiant
+    pub spread: f64,
+
+    /// Time in milli seconds between two shots
+    pub cooldown: f64,
+
+    /// Radius of the projectiles in m
+    pub radius: f64,
+}
+
+/// The kinds of weapons a unit can carry.
+///
+/// Only the kind is transferred over the network, the properties are looked up with
+/// `WeaponKind::weapon`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone, Hash, Default)]
+pub enum WeaponKind {
+    /// Balanced all-round weapon
+    #[default]
+    Rifle,
+    /// Fast firing, short ranged and inaccurate
+    MachineGun,
+    /// Slow, heavy projectiles with a long range
+    Cannon,
+}
+
+impl WeaponKind {
+    pub const ALL: [WeaponKind; 3] = [
+        WeaponKind::Rifle,
+        WeaponKind::MachineGun,
+        WeaponKind::Cannon,
+    ];
+
+    pub fn weapon(self) -> Weapon {
+        match self {
+            WeaponKind::Rifle => Weapon {
+                damage: 10_000,
+                speed: 0.1,
+                range: 400.0,
+                spread: 0.02,
+                cooldown: 500.0,
+                radius: 1.0,
+            },
+            WeaponKind::MachineGun => Weapon {
+                damage: 2_500,
+                speed: 0.15,
+                range: 250.0,
+                spread: 0.1,
+                cooldown: 100.0,
+                radius: 0.5,
+            },
+            WeaponKind::Cannon => Weapon {
+                damage: 30_000,
+                speed: 0.06,
+                range: 600.0,
+                spread: 0.0,
+                cooldown: 2000.0,
+                radius: 3.0,
+            },
+        }
+    }
+}
+
+/// How a unit without an order reacts to enemies.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone, Hash, Default)]
+pub enum Stance {
+    /// Chase and shoot enemies that come close
+    #[default]
+    Aggressive,
+    /// Shoot enemies in range, but don't leave the current position
+    Defensive,
+    /// Never open fire without an explicit order
+    HoldFire,
+}
+
+impl Stance {
+    pub fn next(self) -> Stance {
+        match self {
+            Stance::Aggressive => Stance::Defensive,
+            Stance::Defensive => Stance::HoldFire,
+            Stance::HoldFire => Stance::Aggressive,
+        }
+    }
+}
+
 /// The state of a single unit
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Unit {
@@ -61,6 +198,18 @@ pub struct Unit {
 
     /// Health of the unit
     pub health: u64,
+
+    /// The weapon of the unit
+    pub weapon: WeaponKind,
+
+    /// Time in milli seconds until the weapon can fire again
+    pub cooldown: f64,
+
+    /// Behaviour of the unit while it has no order
+    pub stance: Stance,
+
+    /// Orders the unit executes one after the other
+    pub orders: VecDeque<Order>,
 }
 
 impl Unit {
@@ -71,21 +220,99 @@ impl Unit {
             position,
             angle: 0.0f64,
             speed_vector: Vec2::new(0.0, 0.0),
-            health: 100_000,
+            health: MAX_HEALTH,
+            weapon: WeaponKind::default(),
+            cooldown: 0.0,
+            stance: Stance::default(),
+            orders: VecDeque::new(),
         }
     }
 
     pub fn update(&mut self, dt_ms: f64) {
         self.position += self.speed_vector * dt_ms;
+        self.cooldown = (self.cooldown - dt_ms).max(0.0);
+    }
+
+    /// Rotate the unit towards `target` by at most `max_turn` radiant.
+    ///
+    /// Returns whether the unit faces the target afterwards.
+    pub fn aim_at(&mut self, target: Vec2, max_turn: f64) -> bool {
+        let d = target - self.position;
+        let desired = d.y.atan2(d.x);
+        let delta = (desired - self.angle + PI).rem_euclid(2.0 * PI) - PI;
+        if delta.abs() <= max_turn {
+            self.angle = desired;
+            return true;
+        }
+        self.angle = (self.angle + max_turn * delta.signum()).rem_euclid(2.0 * PI);
+        delta.abs() - max_turn <= AIM_TOLERANCE
+    }
+
+    /// The distance from the center of the unit in which its weapon can hit.
+    pub fn range(&self) -> f64 {
+        // Bullets start at the front of the unit
+        self.weapon.weapon().range + UNIT_SIZE
+    }
+
+    /// The distance from the center of the unit in which it sees other units and bullets.
+    pub fn sight_radius(&self) -> f64 {
+        self.range() + SIGHT_MARGIN
+    }
+
+    /// Replace all orders of the unit with the given one.
+    pub fn order(&mut self, order: Order) {
+        self.orders.clear();
+        self.orders.push_back(order);
+    }
+
+    /// Move towards `target` while turning to face it.
+    pub fn advance(&mut self, target: Vec2, dt_ms: f64) {
+        self.speed_vector = (target - self.position) * UNIT_SPEED;
+        self.aim_at(target, TURN_SPEED * dt_ms);
     }
 
-    pub fn shoot(&self, size: f64, speed: f64) -> Bullet {
+    /// Approach `target` until it is within weapon range, turn towards it and fire once aimed.
+    pub fn engage<R: Rng>(&mut self, target: Vec2, dt_ms: f64, rng: &mut R) -> Option<Bullet> {
+        if (target - self.position).magnitude() <= self.range() {
+            self.fire_at(target, dt_ms, rng)
+        } else {
+            self.advance(target, dt_ms);
+            None
+        }
+    }
+
+    /// Stop, turn towards `target` and fire once aimed, if it is within weapon range.
+    pub fn fire_at<R: Rng>(&mut self, target: Vec2, dt_ms: f64, rng: &mut R) -> Option<Bullet> {
+        self.speed_vector = Vec2::new(0.0, 0.0);
+        let in_range = (target - self.position).magnitude() <= self.range();
+        if self.aim_at(target, TURN_SPEED * dt_ms) && in_range {
+            self.shoot(rng)
+        } else {
+            None
+        }
+    }
+
+    /// Fire the weapon of the unit.
+    ///
+    /// Returns `None` while the weapon is still cooling down.
+    pub fn shoot<R: Rng>(&mut self, rng: &mut R) -> Option<Bullet> {
+        if self.cooldown > 0.0 {
+            return None;
+        }
+        let weapon = self.weapon.weapon();
+        self.cooldown = weapon.cooldown;
+
         let position = Vec2::new(
-            self.position.x + self.angle.cos() * size,
-            self.position.y + self.angle.sin() * size,
+            self.position.x + self.angle.cos() * UNIT_SIZE,
+            self.position.y + self.angle.sin() * UNIT_SIZE,
         );
-        let speed = Vec2::new(self.angle.cos() * speed, self.angle.sin() * speed);
-        Bullet::new(position, speed)
+        let angle = if weapon.spread > 0.0 {
+            self.angle + rng.gen_range(-weapon.spread..=weapon.spread)
+        } else {
+            self.angle
+        };
+        let speed = Vec2::new(angle.cos() * weapon.speed, angle.sin() * weapon.speed);
+        Some(Bullet::new(position, speed, self.weapon))
     }
 }
 
@@ -93,26 +320,70 @@ impl Unit {
 pub struct Bullet {
     pub position: Vec2,
     pub speed_vector: Vec2,
+
+    /// The weapon that fired the bullet
+    pub weapon: WeaponKind,
+
+    /// Remaining distance in m until the bullet expires
+    pub range: f64,
 }
 
 impl Bullet {
-    pub fn new(position: Vec2, speed: Vec2) -> Bullet {
+    pub fn new(position: Vec2, speed: Vec2, weapon: WeaponKind) -> Bullet {
         Bullet {
             position,
             speed_vector: speed,
+            weapon,
+            range: weapon.weapon().range,
         }
     }
 
     pub fn update(&mut self, dt_ms: f64) {
-        self.position += self.speed_vector * dt_ms;
+        let step = self.speed_vector * dt_ms;
+        self.position += step;
+        self.range -= (step.x * step.x + step.y * step.y).sqrt();
+    }
+
+    /// Whether the bullet has travelled its full range
+    pub fn is_expired(&self) -> bool {
+        self.range <= 0.0
     }
 }
 
+/// An order that is executed by a unit over several updates.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum Order {
+    /// Move to the position
+    Move(Vec2),
+    /// Move to the position as part of a formation, by keeping the offset to the leader of the
+    /// formation. Units without a leader, including the leader itself, move on their own.
+    FormationMove {
+        target: Vec2,
+        leader: UnitId,
+        offset: Vec2,
+    },
+    /// Chase the unit and shoot at it until it is destroyed
+    Attack(UnitId),
+    /// Move to the position and engage any enemy that comes into range on the way
+    AttackMove(Vec2),
+    /// Don't move, but shoot enemies in range unless the unit holds fire
+    HoldPosition,
+    /// Attack-move along the waypoints in a loop. The second value is the index of the waypoint
+    /// the unit is heading to.
+    Patrol(Vec<Vec2>, usize),
+}
+
 /// A player has an ID and consists of 0..N `Unit`s
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Player {
     pub id: ClientId,
     pub units: Vec<Unit>,
+
+    /// Index of the player's color, assigned by the server
+    pub color: usize,
+
+    /// Players with the same team share the team chat
+    pub team: usize,
 }
 
 impl Player {
@@ -120,6 +391,8 @@ impl Player {
         Player {
             id: id.into(),
             units: vec![],
+            color: 0,
+            team: 0,
         }
     }
 }
@@ -133,6 +406,15 @@ pub struct GameState {
     /// List of players
     pub players: Vec<Player>,
     pub bullets: Vec<Bullet>,
+    /// Number of simulation steps the server has run
+    pub tick: u64,
+    pub match_state: MatchState,
+    /// The simulation doesn't advance while the game is paused
+    pub paused: bool,
+    /// Multiplier for the time that passes in every simulation step
+    pub speed: f64,
+    /// Players that voted to pause or resume the game, i.e. to change `paused`
+    pub pause_votes: Vec<ClientId>,
 }
 
 impl Default for GameState {
@@ -146,18 +428,274 @@ impl GameState {
         GameState {
             players: vec![],
             bullets: vec![],
+            tick: 0,
+            match_state: MatchState::default(),
+            paused: false,
+            speed: 1.0,
+            pause_votes: vec![],
+        }
+    }
+
+    /// Return the player with the given ID.
+    pub fn player(&self, id: ClientId) -> Option<&Player> {
+        self.players.iter().find(|player| player.id == id)
+    }
+
+    /// Return the player with the given ID for modification.
+    pub fn player_mut(&mut self, id: ClientId) -> Option<&mut Player> {
+        self.players.iter_mut().find(|player| player.id == id)
+    }
+
+    /// Return the lowest color index that isn't used by any player yet, `None` if all colors are
+    /// taken.
+    pub fn unused_color(&self) -> Option<usize> {
+        (0..MAX_PLAYERS).find(|color| self.players.iter().all(|player| player.color != *color))
+    }
+
+    /// Return the lowest team index that no player is in yet, `None` if all teams have players.
+    pub fn unused_team(&self) -> Option<usize> {
+        (0..MAX_TEAMS).find(|team| self.players.iter().all(|player| player.team != *team))
+    }
+
+    /// Handle the request of a player to pause or resume the game.
+    ///
+    /// The host changes the state right away, the requests of other players count as votes and
+    /// the state changes once more than half of the players voted for it.
+    pub fn request_pause(&mut self, id: ClientId, paused: bool, host: Option<ClientId>) {
+        if self.player(id).is_none() {
+            return;
+        }
+        if paused == self.paused {
+            // Take back an earlier vote
+            self.pause_votes.retain(|voter| *voter != id);
+            return;
+        }
+        if !self.pause_votes.contains(&id) {
+            self.pause_votes.push(id);
+        }
+        if host == Some(id) || self.pause_votes.len() * 2 > self.players.len() {
+            self.paused = paused;
+            self.pause_votes.clear();
         }
     }
 
-    pub fn update_targets(&mut self, unit_targets: &HashMap<UnitId, Vec2>) {
+    /// Set the game speed, clamped to the supported range.
+    pub fn set_speed(&mut self, speed: f64) {
+        if speed.is_finite() {
+            self.speed = speed.clamp(MIN_GAME_SPEED, MAX_GAME_SPEED);
+        }
+    }
+
+    /// Return whether the simulation advances, i.e. the match is running and not paused.
+    pub fn is_active(&self) -> bool {
+        self.match_state == MatchState::Running && !self.paused
+    }
+
+    /// Return whether any unit of the player sees the position.
+    pub fn is_visible_to(&self, viewer: ClientId, position: Vec2) -> bool {
+        self.player(viewer).is_some_and(|player| {
+            player
+                .units
+                .iter()
+                .any(|unit| (position - unit.position).magnitude() <= unit.sight_radius())
+        })
+    }
+
+    /// Return the part of the state the player may know about.
+    ///
+    /// Units and bullets of other players are only included while one of the player's units sees
+    /// them, and the orders of other players' units are left out. The players themselves are
+    /// kept, so that the player list stays complete.
+    pub fn visible_to(&self, viewer: ClientId) -> GameState {
+        let mut state = self.clone();
+        for player in state.players.iter_mut().filter(|p| p.id != viewer) {
+            player
+                .units
+                .retain(|unit| self.is_visible_to(viewer, unit.position));
+            for unit in player.units.iter_mut() {
+                unit.orders.clear();
+            }
+        }
+        state
+            .bullets
+            .retain(|bullet| self.is_visible_to(viewer, bullet.position));
+        state
+    }
+
+    /// Return those of the given units that belong to the player.
+    pub fn owned_units(&self, owner: ClientId, ids: &[UnitId]) -> Vec<UnitId> {
+        match self.player(owner) {
+            Some(player) => ids
+                .iter()
+                .filter(|id| player.units.iter().any(|unit| unit.id == **id))
+                .cloned()
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Return the unit with the given ID together with the ID of the owning player.
+    pub fn find_unit(&self, id: UnitId) -> Option<(ClientId, &Unit)> {
+        self.players.iter().find_map(|player| {
+            player
+                .units
+                .iter()
+                .find(|unit| unit.id == id)
+                .map(|unit| (player.id, unit))
+        })
+    }
+
+    /// Execute the orders of all units for a step of `dt` milli seconds.
+    ///
+    /// Every unit works on the first order in its queue. Orders that are completed or can't be
+    /// executed anymore, e.g. because the attacked unit was destroyed or can't be seen anymore,
+    /// are removed from the queue.
+    pub fn update_orders<R: Rng>(&mut self, dt: f64, rng: &mut R) {
+        // Snapshot of all units, so that units can look at each other while being updated
+        let positions: Vec<(ClientId, UnitId, Vec2, Vec2)> = self
+            .players
+            .iter()
+            .flat_map(|player| {
+                player
+                    .units
+                    .iter()
+                    .map(move |unit| (player.id, unit.id, unit.position, unit.speed_vector))
+            })
+            .collect();
+        let sights: Vec<(ClientId, Vec2, f64)> = self
+            .players
+            .iter()
+            .flat_map(|player| {
+                player
+                    .units
+                    .iter()
+                    .map(move |unit| (player.id, unit.position, unit.sight_radius()))
+            })
+            .collect();
+        // Same as `is_visible_to`, but on the snapshot
+        let is_visible = |viewer: ClientId, position: Vec2| {
+            sights
+                .iter()
+                .any(|(owner, p, sight)| *owner == viewer && (position - p).magnitude() <= *sight)
+        };
+        // Units only pick up enemies their owner sees, so that they don't give away positions
+        // hidden in the fog of war
+        let nearest_enemy = |owner: ClientId, position: Vec2, range: f64| {
+            positions
+                .iter()
+                .filter(|(id, _, p, _)| *id != owner && is_visible(owner, *p))
+                .map(|(_, _, p, _)| (*p, (p - position).magnitude()))
+                .filter(|(_, distance)| *distance <= range)
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(p, _)| p)
+        };
+
         for player in self.players.iter_mut() {
+            let owner = player.id;
             for unit in player.units.iter_mut() {
-                if let Some(target) = unit_targets.get(&unit.id) {
-                    let speed = 0.0001;
-                    unit.speed_vector = (target - unit.position) * speed;
-                } else {
+                let mut done = false;
+                let mut order = unit.orders.pop_front();
+                let bullet = match order {
+                    Some(Order::Move(target)) => {
+                        unit.advance(target, dt);
+                        done = (target - unit.position).magnitude() <= ARRIVAL_DISTANCE;
+                        None
+                    }
+                    Some(Order::FormationMove {
+                        target,
+                        leader,
+                        offset,
+                    }) => {
+                        let leader = positions
+                            .iter()
+                            .find(|(_, id, _, _)| *id == leader && *id != unit.id);
+                        match leader {
+                            Some((_, _, position, speed_vector)) => {
+                                // Move along with the leader and correct the distance to the
+                                // slot in the formation
+                                let slot = position + offset;
+                                unit.speed_vector =
+                                    speed_vector + (slot - unit.position) * FORMATION_SPEED;
+                                unit.aim_at(target, TURN_SPEED * dt);
+                            }
+                            None => unit.advance(target, dt),
+                        }
+                        done = (target - unit.position).magnitude() <= ARRIVAL_DISTANCE;
+                        None
+                    }
+                    Some(Order::Attack(target_id)) => {
+                        // Units don't chase targets that disappeared in the fog of war
+                        match positions
+                            .iter()
+                            .find(|(_, id, p, _)| *id == target_id && is_visible(owner, *p))
+                        {
+                            Some((_, _, target, _)) => unit.engage(*target, dt, rng),
+                            None => {
+                                unit.speed_vector = Vec2::new(0.0, 0.0);
+                                done = true;
+                                None
+                            }
+                        }
+                    }
+                    Some(Order::AttackMove(target)) => {
+                        match nearest_enemy(player.id, unit.position, unit.range()) {
+                            Some(enemy) => unit.engage(enemy, dt, rng),
+                            None => {
+                                unit.advance(target, dt);
+                                done = (target - unit.position).magnitude() <= ARRIVAL_DISTANCE;
+                                None
+                            }
+                        }
+                    }
+                    Some(Order::HoldPosition) => {
+                        unit.speed_vector = Vec2::new(0.0, 0.0);
+                        if unit.stance == Stance::HoldFire {
+                            None
+                        } else {
+                            nearest_enemy(player.id, unit.position, unit.range())
+                                .and_then(|enemy| unit.fire_at(enemy, dt, rng))
+                        }
+                    }
+                    Some(Order::Patrol(ref waypoints, ref mut next)) => {
+                        match nearest_enemy(player.id, unit.position, unit.range()) {
+                            Some(enemy) => unit.engage(enemy, dt, rng),
+                            None => {
+                                let waypoint = waypoints[*next];
+                                if (waypoint - unit.position).magnitude() <= ARRIVAL_DISTANCE {
+                                    *next = (*next + 1) % waypoints.len();
+                                }
+                                unit.advance(waypoints[*next], dt);
+                                None
+                            }
+                        }
+                    }
+                    // Idle units pick their targets on their own, depending on their stance
+                    None => {
+                        unit.speed_vector = Vec2::new(0.0, 0.0);
+                        match unit.stance {
+                            // Aggressive units chase every enemy they see
+                            Stance::Aggressive => {
+                                nearest_enemy(player.id, unit.position, unit.sight_radius())
+                                    .and_then(|enemy| unit.engage(enemy, dt, rng))
+                            }
+                            Stance::Defensive => {
+                                nearest_enemy(player.id, unit.position, unit.range())
+                                    .and_then(|enemy| unit.fire_at(enemy, dt, rng))
+                            }
+                            Stance::HoldFire => None,
+                        }
+                    }
+                };
+                if let Some(order) = order.take() {
+                    if !done {
+                        unit.orders.push_front(order);
+                    }
+                }
+                // A unit that completed its last order stops instead of overshooting the target
+                if done && unit.orders.is_empty() {
                     unit.speed_vector = Vec2::new(0.0, 0.0);
                 }
+                self.bullets.extend(bullet);
             }
         }
 
@@ -200,38 +738,35 @@ impl GameState {
     }
 
     pub fn update(&mut self, world: &WorldState, dt: f64) {
-        for bullet in self.bullets.iter_mut() {
-            bullet.update(dt);
-        }
-
         // waiting for non-lexical lifetimes...
         {
             let bullets = &mut self.bullets;
             let players = &mut self.players;
-            bullets.retain(|bullet| {
-                // still inside world?
-                if bullet.position[0] > world.x
-                    || bullet.position[1] > world.y
-                    || bullet.position[0] < 0.0
-                    || bullet.position[1] < 0.0
-                {
-                    return false;
-                }
+            bullets.retain_mut(|bullet| {
+                // Test the whole path travelled during this step, so that fast bullets can't
+                // tunnel through a unit.
+                let start = bullet.position;
+                bullet.update(dt);
 
                 for player in players.iter_mut() {
                     for unit in player.units.iter_mut() {
-                        if unit.health > 0 && unit.is_hit(UNIT_SIZE, bullet.position) {
-                            if unit.health > 10000 {
-                                unit.health -= 10000;
-                            } else {
-                                unit.health = 0;
-                            }
+                        let weapon = bullet.weapon.weapon();
+                        if unit.health > 0
+                            && unit.is_swept_hit(UNIT_SIZE, start, bullet.position, weapon.radius)
+                        {
+                            unit.health = unit.health.saturating_sub(weapon.damage);
                             info!("hit: {}", unit.health);
                             return false;
                         }
                     }
                 }
-                true
+
+                // still inside world and range?
+                !(bullet.is_expired()
+                    || bullet.position[0] > world.x
+                    || bullet.position[1] > world.y
+                    || bullet.position[0] < 0.0
+                    || bullet.position[1] < 0.0)
             });
         }
 
@@ -244,12 +779,21 @@ impl GameState {
         }
     }
 
-    pub fn shoot(&mut self, id: UnitId) {
+    /// Return the unit with the given ID for modification.
+    pub fn find_unit_mut(&mut self, id: UnitId) -> Option<&mut Unit> {
+        self.players
+            .iter_mut()
+            .flat_map(|player| player.units.iter_mut())
+            .find(|unit| unit.id == id)
+    }
+
+    pub fn shoot<R: Rng>(&mut self, id: UnitId, rng: &mut R) {
         for player in self.players.iter_mut() {
-            for unit in player.units.iter() {
+            for unit in player.units.iter_mut() {
                 if unit.id == id {
-                    let bullet = unit.shoot(UNIT_SIZE, 0.1);
-                    self.bullets.push(bullet);
+                    if let Some(bullet) = unit.shoot(rng) {
+                        self.bullets.push(bullet);
+                    }
                 }
             }
         }
@@ -271,6 +815,14 @@ impl WorldState {
     pub fn new(x: f64, y: f64) -> WorldState {
         WorldState { x, y }
     }
+
+    /// Return the position inside the world that is closest to `position`.
+    pub fn clamp(&self, position: Vec2) -> Vec2 {
+        Vec2::new(
+            clamp(position.x, 0.0, self.x),
+            clamp(position.y, 0.0, self.y),
+        )
+    }
 }
 
 #[cfg(test)]
@@ -309,7 +861,7 @@ mod tests {
         //! speed vector is defined.
         let pos = Vec2::new(20.0, 10.0);
         let speed = Vec2::new(0.0, 0.0);
-        let mut bullet = Bullet::new(pos, speed);
+        let mut bullet = Bullet::new(pos, speed, WeaponKind::Rifle);
         assert_eq!(bullet.position, pos);
         bullet.update(10.0);
         assert_eq!(bullet.position, pos);
@@ -321,11 +873,495 @@ mod tests {
     fn test_bullet_update_moving() {
         //! The position of a bullet should not change on update when no speed
         //! vector is defined.
-        let mut bullet = Bullet::new(Vec2::new(20.0, 10.0), Vec2::new(1.0, 2.0));
+        let mut bullet = Bullet::new(
+            Vec2::new(20.0, 10.0),
+            Vec2::new(1.0, 2.0),
+            WeaponKind::Rifle,
+        );
         assert_eq!(bullet.position, Vec2::new(20.0, 10.0));
         bullet.update(1.0);
         assert_eq!(bullet.position, Vec2::new(21.0, 12.0));
         bullet.update(100.0);
         assert_eq!(bullet.position, Vec2::new(121.0, 212.0));
     }
+
+    #[test]
+    fn test_fast_bullet_does_not_tunnel() {
+        //! A bullet that passes a unit within a single update must still hit it.
+        let world = WorldState::new(1000.0, 1000.0);
+        let mut game = GameState::new();
+        let mut player = Player::new(0);
+        player.units.push(Unit::new(0, Vec2::new(500.0, 500.0)));
+        game.players.push(player);
+        game.bullets.push(Bullet::new(
+            Vec2::new(100.0, 500.0),
+            Vec2::new(1.0, 0.0),
+            WeaponKind::Cannon,
+        ));
+
+        game.update(&world, 800.0);
+
+        assert!(game.bullets.is_empty());
+        assert!(game.players[0].units[0].health < 100_000);
+    }
+
+    #[test]
+    fn test_bullet_expires_after_range() {
+        //! A bullet is removed once it has travelled the range of its weapon.
+        let world = WorldState::new(10_000.0, 10_000.0);
+        let mut game = GameState::new();
+        let weapon = WeaponKind::Rifle.weapon();
+        game.bullets.push(Bullet::new(
+            Vec2::new(0.0, 5000.0),
+            Vec2::new(weapon.speed, 0.0),
+            WeaponKind::Rifle,
+        ));
+
+        game.update(&world, 0.9 * weapon.range / weapon.speed);
+        assert_eq!(game.bullets.len(), 1);
+        game.update(&world, 0.2 * weapon.range / weapon.speed);
+        assert!(game.bullets.is_empty());
+    }
+
+    #[test]
+    fn test_unit_shoot_cooldown() {
+        //! A unit can only shoot again once the cooldown of its weapon has passed.
+        let mut rng = rand::thread_rng();
+        let mut unit = Unit::new(1, Vec2::new(0.0, 0.0));
+        unit.weapon = WeaponKind::Cannon;
+        let weapon = unit.weapon.weapon();
+
+        let bullet = unit.shoot(&mut rng).expect("first shot");
+        assert_eq!(bullet.weapon, WeaponKind::Cannon);
+        assert_eq!(bullet.range, weapon.range);
+        assert!(unit.shoot(&mut rng).is_none());
+
+        unit.update(weapon.cooldown);
+        assert!(unit.shoot(&mut rng).is_some());
+    }
+
+    #[test]
+    fn test_unit_aim_at() {
+        //! A unit turns towards its target by the given step and reports when it faces it.
+        let mut unit = Unit::new(1, Vec2::new(0.0, 0.0));
+        let target = Vec2::new(0.0, -10.0);
+
+        assert!(!unit.aim_at(target, 0.5));
+        assert_eq!(unit.angle, 2.0 * PI - 0.5);
+        assert!(unit.aim_at(target, 2.0));
+        assert_eq!(unit.angle, -PI / 2.0);
+    }
+
+    #[test]
+    fn test_attack_order() {
+        //! A unit with an attack order turns towards its target, shoots it and forgets the order
+        //! once the target is destroyed.
+        let mut rng = rand::thread_rng();
+        let world = WorldState::new(1000.0, 1000.0);
+        let mut game = GameState::new();
+        let mut attacker = Player::new(0);
+        let mut unit = Unit::new(0, Vec2::new(100.0, 100.0));
+        unit.weapon = WeaponKind::Cannon;
+        unit.order(Order::Attack(UnitId(1)));
+        attacker.units.push(unit);
+        let mut defender = Player::new(1);
+        let mut target = Unit::new(1, Vec2::new(100.0, 400.0));
+        target.stance = Stance::HoldFire;
+        defender.units.push(target);
+        game.players.push(attacker);
+        game.players.push(defender);
+
+        for _ in 0..10_000 {
+            game.update_orders(5.0, &mut rng);
+            game.update(&world, 5.0);
+        }
+
+        assert!(game.players[1].units.is_empty());
+        assert!(game.players[0].units[0].orders.is_empty());
+        assert_eq!(game.players[0].units[0].position, Vec2::new(100.0, 100.0));
+    }
+
+    #[test]
+    fn test_attack_order_lost_in_fog() {
+        //! A unit gives up its attack once the target left its owner's sight.
+        let mut rng = rand::thread_rng();
+        let mut game = duel(100.0, Stance::HoldFire);
+        game.players[0].units[0].order(Order::Attack(UnitId(1)));
+        game.update_orders(5.0, &mut rng);
+        assert_eq!(
+            game.players[0].units[0].orders.front(),
+            Some(&Order::Attack(UnitId(1)))
+        );
+
+        let sight = game.players[0].units[0].sight_radius();
+        game.players[1].units[0].position = Vec2::new(100.0, 100.0 + sight + 1.0);
+        game.update_orders(5.0, &mut rng);
+        assert!(game.players[0].units[0].orders.is_empty());
+        assert_eq!(game.players[0].units[0].speed_vector, Vec2::new(0.0, 0.0));
+    }
+
+    /// Create a game with a unit of player 0 at (100, 100) and an enemy unit of player 1 at
+    /// (100, 100 + `distance`).
+    fn duel(distance: f64, stance: Stance) -> GameState {
+        let mut game = GameState::new();
+        let mut player = Player::new(0);
+        let mut unit = Unit::new(0, Vec2::new(100.0, 100.0));
+        unit.stance = stance;
+        player.units.push(unit);
+        let mut enemy = Player::new(1);
+        let mut enemy_unit = Unit::new(1, Vec2::new(100.0, 100.0 + distance));
+        enemy_unit.stance = Stance::HoldFire;
+        enemy.units.push(enemy_unit);
+        game.players.push(player);
+        game.players.push(enemy);
+        game
+    }
+
+    #[test]
+    fn test_idle_stances() {
+        //! Idle units react to an enemy in weapon range according to their stance.
+        let mut rng = rand::thread_rng();
+        let distance = WeaponKind::Rifle.weapon().range;
+
+        for (stance, fires) in [
+            (Stance::Aggressive, true),
+            (Stance::Defensive, true),
+            (Stance::HoldFire, false),
+        ] {
+            let mut game = duel(distance, stance);
+            for _ in 0..100 {
+                game.update_orders(5.0, &mut rng);
+            }
+            assert_eq!(!game.bullets.is_empty(), fires, "{:?}", stance);
+            assert_eq!(game.players[0].units[0].speed_vector, Vec2::new(0.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn test_idle_aggressive_chases() {
+        //! Only aggressive units chase enemies out of weapon range, as long as they see them.
+        let mut rng = rand::thread_rng();
+        let sight = Unit::new(0, Vec2::new(0.0, 0.0)).sight_radius();
+
+        let mut game = duel(sight - 10.0, Stance::Aggressive);
+        game.update_orders(5.0, &mut rng);
+        assert!(game.players[0].units[0].speed_vector.y > 0.0);
+
+        let mut game = duel(sight - 10.0, Stance::Defensive);
+        game.update_orders(5.0, &mut rng);
+        assert_eq!(game.players[0].units[0].speed_vector, Vec2::new(0.0, 0.0));
+
+        let mut game = duel(sight + 10.0, Stance::Aggressive);
+        game.update_orders(5.0, &mut rng);
+        assert_eq!(game.players[0].units[0].speed_vector, Vec2::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn test_fogged_enemy_ignored() {
+        //! Aggressive units of every weapon kind don't chase enemies hidden in the fog of war.
+        let mut rng = rand::thread_rng();
+
+        for weapon in [
+            WeaponKind::Rifle,
+            WeaponKind::MachineGun,
+            WeaponKind::Cannon,
+        ] {
+            let mut game = duel(10_000.0, Stance::Aggressive);
+            let unit = &mut game.players[0].units[0];
+            unit.weapon = weapon;
+            let distance = 1.5 * unit.range();
+            game.players[1].units[0].position = Vec2::new(100.0, 100.0 + distance);
+            assert!(!game.is_visible_to(ClientId(0), game.players[1].units[0].position));
+
+            for _ in 0..100 {
+                game.update_orders(5.0, &mut rng);
+            }
+            let unit = &game.players[0].units[0];
+            assert_eq!(unit.speed_vector, Vec2::new(0.0, 0.0), "{:?}", weapon);
+            assert_eq!(unit.angle, 0.0, "{:?}", weapon);
+            assert!(game.bullets.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_stances_after_move() {
+        //! Units that finished a move react to enemies according to their stance.
+        let mut rng = rand::thread_rng();
+
+        for (stance, chases, fires) in [
+            (Stance::Aggressive, true, true),
+            (Stance::Defensive, false, true),
+            (Stance::HoldFire, false, false),
+        ] {
+            let mut game = duel(10_000.0, stance);
+            game.players[0].units[0].order(Order::Move(Vec2::new(100.0, 110.0)));
+            game.update_orders(5.0, &mut rng);
+            assert!(game.players[0].units[0].orders.is_empty(), "{:?}", stance);
+
+            // Just out of weapon range
+            let range = game.players[0].units[0].range();
+            game.players[1].units[0].position = Vec2::new(100.0, 120.0 + range);
+            game.update_orders(5.0, &mut rng);
+            let speed = game.players[0].units[0].speed_vector;
+            assert_eq!(speed.y > 0.0, chases, "{:?}", stance);
+
+            // In weapon range
+            game.players[1].units[0].position = Vec2::new(100.0, 80.0 + range);
+            for _ in 0..100 {
+                game.update_orders(5.0, &mut rng);
+            }
+            assert_eq!(!game.bullets.is_empty(), fires, "{:?}", stance);
+        }
+    }
+
+    #[test]
+    fn test_patrol_order() {
+        //! A unit on patrol turns around at the last waypoint and heads back to the first one.
+        let mut rng = rand::thread_rng();
+        let mut game = GameState::new();
+        let mut player = Player::new(0);
+        let mut unit = Unit::new(0, Vec2::new(100.0, 100.0));
+        let waypoints = vec![Vec2::new(100.0, 100.0), Vec2::new(400.0, 100.0)];
+        unit.order(Order::Patrol(waypoints, 1));
+        player.units.push(unit);
+        game.players.push(player);
+
+        game.update_orders(5.0, &mut rng);
+        assert!(game.players[0].units[0].speed_vector.x > 0.0);
+
+        game.players[0].units[0].position = Vec2::new(390.0, 100.0);
+        game.update_orders(5.0, &mut rng);
+        assert!(game.players[0].units[0].speed_vector.x < 0.0);
+        assert!(matches!(
+            game.players[0].units[0].orders[0],
+            Order::Patrol(_, 0)
+        ));
+    }
+
+    #[test]
+    fn test_order_queue() {
+        //! Queued move orders are executed one after the other until the unit is idle.
+        let mut rng = rand::thread_rng();
+        let mut game = GameState::new();
+        let mut player = Player::new(0);
+        let mut unit = Unit::new(0, Vec2::new(100.0, 100.0));
+        unit.order(Order::Move(Vec2::new(400.0, 100.0)));
+        unit.orders.push_back(Order::Move(Vec2::new(400.0, 400.0)));
+        player.units.push(unit);
+        game.players.push(player);
+
+        game.update_orders(5.0, &mut rng);
+        assert_eq!(game.players[0].units[0].orders.len(), 2);
+        assert!(game.players[0].units[0].speed_vector.x > 0.0);
+
+        game.players[0].units[0].position = Vec2::new(390.0, 100.0);
+        game.update_orders(5.0, &mut rng);
+        assert_eq!(game.players[0].units[0].orders.len(), 1);
+
+        game.players[0].units[0].position = Vec2::new(400.0, 300.0);
+        game.update_orders(5.0, &mut rng);
+        assert_eq!(game.players[0].units[0].orders.len(), 1);
+        assert!(game.players[0].units[0].speed_vector.y > 0.0);
+
+        game.players[0].units[0].position = Vec2::new(400.0, 390.0);
+        game.update_orders(5.0, &mut rng);
+        assert!(game.players[0].units[0].orders.is_empty());
+        assert_eq!(game.players[0].units[0].speed_vector, Vec2::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn test_formation_move() {
+        //! Units in formation keep their offset to the leader while moving.
+        let mut rng = rand::thread_rng();
+        let world = WorldState::new(10_000.0, 10_000.0);
+        let mut game = GameState::new();
+        let mut player = Player::new(0);
+        let offset = Vec2::new(0.0, 200.0);
+        let target = Vec2::new(5000.0, 1000.0);
+        let mut leader = Unit::new(0, Vec2::new(1000.0, 1000.0));
+        leader.order(Order::FormationMove {
+            target,
+            leader: UnitId(0),
+            offset: Vec2::new(0.0, 0.0),
+        });
+        let mut follower = Unit::new(1, Vec2::new(1000.0, 1000.0) + offset);
+        follower.order(Order::FormationMove {
+            target: target + offset,
+            leader: UnitId(0),
+            offset,
+        });
+        player.units.push(leader);
+        player.units.push(follower);
+        game.players.push(player);
+
+        for _ in 0..1000 {
+            game.update_orders(5.0, &mut rng);
+            game.update(&world, 5.0);
+        }
+
+        let units = &game.players[0].units;
+        assert!(units[0].position.x > 2000.0);
+        assert!(((units[1].position - units[0].position) - offset).magnitude() < 1.0);
+    }
+
+    #[test]
+    fn test_formation_move_finishes() {
+        //! Units in formation become idle once they reached their slot at the target.
+        let mut rng = rand::thread_rng();
+        let world = WorldState::new(10_000.0, 10_000.0);
+        let mut game = GameState::new();
+        let mut player = Player::new(0);
+        let offset = Vec2::new(0.0, 200.0);
+        let target = Vec2::new(1500.0, 1000.0);
+        for (id, unit_offset) in [(0, Vec2::new(0.0, 0.0)), (1, offset)] {
+            let mut unit = Unit::new(id, Vec2::new(1000.0, 1000.0) + unit_offset);
+            unit.order(Order::FormationMove {
+                target: target + unit_offset,
+                leader: UnitId(0),
+                offset: unit_offset,
+            });
+            player.units.push(unit);
+        }
+        game.players.push(player);
+
+        for _ in 0..10_000 {
+            game.update_orders(5.0, &mut rng);
+            game.update(&world, 5.0);
+        }
+
+        for unit in game.players[0].units.iter() {
+            assert!(unit.orders.is_empty());
+            assert_eq!(unit.speed_vector, Vec2::new(0.0, 0.0));
+            assert!((unit.position - target).magnitude() < 250.0);
+        }
+    }
+
+    #[test]
+    fn test_attack_move_finishes() {
+        //! An attack move without enemies on the way ends at the target.
+        let mut rng = rand::thread_rng();
+        let mut game = GameState::new();
+        let mut player = Player::new(0);
+        let mut unit = Unit::new(0, Vec2::new(100.0, 100.0));
+        unit.order(Order::AttackMove(Vec2::new(400.0, 100.0)));
+        player.units.push(unit);
+        game.players.push(player);
+
+        game.update_orders(5.0, &mut rng);
+        assert!(game.players[0].units[0].speed_vector.x > 0.0);
+
+        game.players[0].units[0].position = Vec2::new(390.0, 100.0);
+        game.update_orders(5.0, &mut rng);
+        assert!(game.players[0].units[0].orders.is_empty());
+        assert_eq!(game.players[0].units[0].speed_vector, Vec2::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn test_player_lookup() {
+        //! Players are found by their ID, independent of their position in the list.
+        let mut game = GameState::new();
+        let mut player = Player::new(7);
+        player.units.push(Unit::new(1, Vec2::new(0.0, 0.0)));
+        game.players.push(Player::new(3));
+        game.players.push(player);
+
+        assert_eq!(game.player(ClientId(7)).unwrap().units.len(), 1);
+        assert!(game.player(ClientId(1)).is_none());
+        assert_eq!(
+            game.owned_units(ClientId(7), &[UnitId(1), UnitId(2)]),
+            vec![UnitId(1)]
+        );
+        assert!(game.owned_units(ClientId(3), &[UnitId(1)]).is_empty());
+    }
+
+    #[test]
+    fn test_unused_color() {
+        //! Colors of players that left are handed out again, others are kept.
+        let mut game = GameState::new();
+        for id in 0..3 {
+            let mut player = Player::new(id);
+            player.color = game.unused_color().unwrap();
+            game.players.push(player);
+        }
+        assert_eq!(game.unused_color(), Some(3));
+
+        game.players.remove(1);
+        assert_eq!(game.players[1].color, 2);
+        assert_eq!(game.unused_color(), Some(1));
+
+        // All colors taken
+        for id in 3..=MAX_PLAYERS as u32 {
+            let mut player = Player::new(id);
+            player.color = game.unused_color().unwrap();
+            player.team = game.unused_team().unwrap();
+            game.players.push(player);
+        }
+        assert_eq!(game.unused_color(), None);
+        for player in game.players.iter_mut() {
+            player.team = 0;
+        }
+        assert_eq!(game.unused_team(), Some(1));
+    }
+
+    #[test]
+    fn test_visibility() {
+        //! Enemies out of sight are removed from the player's view, their orders are hidden.
+        let mut game = GameState::new();
+        let mut player = Player::new(1);
+        player.units.push(Unit::new(1, Vec2::new(0.0, 0.0)));
+        let sight = player.units[0].sight_radius();
+        let mut enemy = Player::new(2);
+        let mut near = Unit::new(2, Vec2::new(sight - 1.0, 0.0));
+        near.order(Order::Move(Vec2::new(0.0, 0.0)));
+        enemy.units.push(near);
+        enemy.units.push(Unit::new(3, Vec2::new(sight + 1.0, 0.0)));
+        game.players.push(player);
+        game.players.push(enemy);
+
+        let view = game.visible_to(ClientId(1));
+        assert_eq!(view.players.len(), 2);
+        assert_eq!(view.players[0], game.players[0]);
+        let enemy_units = &view.player(ClientId(2)).unwrap().units;
+        assert_eq!(enemy_units.len(), 1);
+        assert_eq!(enemy_units[0].id, UnitId(2));
+        assert!(enemy_units[0].orders.is_empty());
+
+        // Clients without a player see no units at all
+        let view = game.visible_to(ClientId(3));
+        assert!(view.players.iter().all(|player| player.units.is_empty()));
+    }
+
+    #[test]
+    fn test_pause_votes() {
+        //! The host pauses right away, other players need a majority.
+        let mut game = GameState::new();
+        for id in 0..3 {
+            game.players.push(Player::new(id));
+        }
+        let host = Some(ClientId(0));
+        game.request_pause(ClientId(1), true, host);
+        assert!(!game.paused);
+        game.request_pause(ClientId(5), true, host);
+        assert_eq!(game.pause_votes, vec![ClientId(1)]);
+        game.request_pause(ClientId(2), true, host);
+        assert!(game.paused);
+        assert!(game.pause_votes.is_empty());
+
+        game.request_pause(ClientId(1), false, host);
+        game.request_pause(ClientId(1), true, host);
+        assert!(game.pause_votes.is_empty(), "vote was taken back");
+        game.request_pause(ClientId(0), false, host);
+        assert!(!game.paused);
+    }
+
+    #[test]
+    fn test_speed() {
+        let mut game = GameState::new();
+        game.set_speed(2.0);
+        assert_eq!(game.speed, 2.0);
+        game.set_speed(100.0);
+        assert_eq!(game.speed, MAX_GAME_SPEED);
+        game.set_speed(f64::NAN);
+        assert_eq!(game.speed, MAX_GAME_SPEED);
+    }
 }